@@ -15,6 +15,11 @@ fi
 groupadd reflectron
 usermod -a -G reflectron $SUDO_USER
 
+# Members of reflectron-admin may run recipe hooks and 'ref image exec' scripts without
+# authenticating. Those scripts run as root in a chroot with /dev and /proc, which is root
+# on this host, so add only users who are trusted with root here.
+groupadd reflectron-admin
+
 # Install polkit rules
 cp ./99-reflectron.rules /etc/polkit-1/rules.d/
 chown root:root /etc/polkit-1/rules.d/99-reflectron.rules
@@ -23,12 +28,22 @@ chmod 644 /etc/polkit-1/rules.d/99-reflectron.rules
 # Create necessary directories
 mkdir -p /opt/reflectron/images
 mkdir /opt/reflectron/database
-chown :reflectron /opt/reflectron /opt/reflectron/database /opt/reflectron/images
-chmod 775 /opt/reflectron /opt/reflectron/database /opt/reflectron/images
+mkdir /opt/reflectron/vms
+mkdir /opt/reflectron/machines
+mkdir /opt/reflectron/zbm
+chown :reflectron /opt/reflectron /opt/reflectron/database /opt/reflectron/images /opt/reflectron/vms /opt/reflectron/machines /opt/reflectron/zbm
+chmod 775 /opt/reflectron /opt/reflectron/database /opt/reflectron/images /opt/reflectron/vms /opt/reflectron/machines /opt/reflectron/zbm
 chmod g+s /opt/reflectron/database
 
+# Machine encryption keys, readable only by the user who created them
+mkdir /opt/reflectron/keys
+chown root:reflectron /opt/reflectron/keys
+chmod 770 /opt/reflectron/keys
+
 mkdir /var/log/reflectron
 chown :reflectron /var/log/reflectron
 chmod 775 /var/log/reflectron
 
+echo "Running hooks and 'ref image exec' asks for an administrator's password unless you are in reflectron-admin,"
+echo "since it is root on this host. To allow it, run: sudo usermod -a -G reflectron-admin $SUDO_USER"
 echo "Setup completed. Please log out and log back in for group changes to take effect."
\ No newline at end of file
@@ -0,0 +1,181 @@
+use std::fs;
+use crate::*;
+use crate::machine::Machine;
+use serde::{Serialize, Deserialize};
+
+/// Authorized keys are kept outside home directories, root owned, so they can be installed
+/// into images and machine overlays without knowing the users' ids.
+pub const AUTHORIZED_KEYS_DIR: &str = "/etc/ssh/authorized_keys";
+pub const SSHD_CONFIG_PATH: &str = "/etc/ssh/sshd_config.d/reflectron.conf";
+pub const SUDOERS_DIR: &str = "/etc/sudoers.d";
+
+const ROOT_LOGIN_POLICIES: &[&str] = &["yes", "no", "prohibit-password", "forced-commands-only"];
+
+
+/// A login account created in an image.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Public keys, one OpenSSH authorized_keys line each
+    #[serde(default)]
+    pub authorized_keys: Vec<String>,
+    /// sudoers rule for the user, e.g. "ALL=(ALL:ALL) NOPASSWD: ALL"
+    #[serde(default)]
+    pub sudo: Option<String>,
+}
+
+/// sshd login policy.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ssh {
+    #[serde(default = "default_permit_root_login")]
+    pub permit_root_login: String,
+    #[serde(default)]
+    pub password_authentication: bool,
+}
+
+impl Default for Ssh {
+    fn default() -> Ssh {
+        Ssh {
+            permit_root_login: default_permit_root_login(),
+            password_authentication: false,
+        }
+    }
+}
+
+fn default_permit_root_login() -> String {
+    "prohibit-password".to_owned()
+}
+
+/// What a machine adds to its image's accounts: more keys and sudo rules for users that
+/// exist in the image (or root), and its own sshd policy.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Access {
+    #[serde(default)]
+    pub users: Vec<Login>,
+    #[serde(default)]
+    pub ssh: Option<Ssh>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Login {
+    pub name: String,
+    #[serde(default)]
+    pub authorized_keys: Vec<String>,
+    #[serde(default)]
+    pub sudo: Option<String>,
+}
+
+
+pub fn valid_user_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32
+        && name.chars().next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// One authorized_keys line: optional options are not supported, only `<type> <key> [comment]`.
+pub fn check_key(user: &str, key: &str) {
+    let mut parts = key.split_whitespace();
+    let valid = matches!(parts.next(), Some(t) if t.starts_with("ssh-") || t.starts_with("ecdsa-") || t.starts_with("sk-"))
+        && parts.next().is_some_and(|k| k.chars().all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c)))
+        && !key.contains('\n');
+    if !valid {
+        halt!("Invalid authorized key for user {}: {}", user, key);
+    }
+}
+
+pub fn check_sudo(user: &str, rule: &str) {
+    if rule.trim().is_empty() || rule.contains('\n') || rule.contains('\\') {
+        halt!("Invalid sudo rule for user {}: {}", user, rule);
+    }
+}
+
+impl Ssh {
+    pub fn check(&self) {
+        if !ROOT_LOGIN_POLICIES.contains(&self.permit_root_login.as_str()) {
+            halt!("Invalid permit_root_login {}. Valid values are: {}", self.permit_root_login, ROOT_LOGIN_POLICIES.join(", "));
+        }
+    }
+
+    /// sshd drop-in applying the policy and reading keys from `AUTHORIZED_KEYS_DIR`.
+    pub fn sshd_config(&self) -> String {
+        format!(
+            "# Managed by reflectron\nPermitRootLogin {}\nPasswordAuthentication {}\nAuthorizedKeysFile .ssh/authorized_keys {}/%u\n",
+            self.permit_root_login,
+            if self.password_authentication { "yes" } else { "no" },
+            AUTHORIZED_KEYS_DIR
+        )
+    }
+}
+
+impl User {
+    pub fn check(&self) {
+        if !valid_user_name(&self.name) {
+            halt!("Invalid user name {}", self.name);
+        }
+        for group in &self.groups {
+            if !valid_user_name(group) {
+                halt!("Invalid group name {} for user {}", group, self.name);
+            }
+        }
+        if let Some(shell) = &self.shell {
+            if !shell.starts_with('/') || !shell.chars().all(|c| c.is_ascii_lowercase() || c == '/') {
+                halt!("Invalid shell {} for user {}", shell, self.name);
+            }
+        }
+        for key in &self.authorized_keys {
+            check_key(&self.name, key);
+        }
+        if let Some(rule) = &self.sudo {
+            check_sudo(&self.name, rule);
+        }
+    }
+}
+
+impl Access {
+    pub fn check(&self) {
+        for login in &self.users {
+            if !valid_user_name(&login.name) {
+                halt!("Invalid user name {}", login.name);
+            }
+            for key in &login.authorized_keys {
+                check_key(&login.name, key);
+            }
+            if let Some(rule) = &login.sudo {
+                check_sudo(&login.name, rule);
+            }
+        }
+        if let Some(ssh) = &self.ssh {
+            ssh.check();
+        }
+    }
+}
+
+
+pub fn authorized_keys_path(user: &str) -> String {
+    format!("{}/{}", AUTHORIZED_KEYS_DIR, user)
+}
+
+/// The sudoers drop-in for a user. `scope` keeps image and machine rules in separate files.
+pub fn sudoers_path(scope: &str, user: &str) -> String {
+    format!("{}/reflectron-{}-{}", SUDOERS_DIR, scope, user)
+}
+
+pub fn sudoers(user: &str, rule: &str) -> String {
+    format!("# Managed by reflectron\n{} {}\n", user, rule)
+}
+
+
+/// Load a machine's access file and store it with the machine, replacing any earlier one.
+pub fn set(machine_name: &str, file: &str) {
+    let mut machine: Machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| halt!("Could not read access file {}: {}", file, e));
+    let access: Access = serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse access file {}: {}", file, e));
+    access.check();
+    machine.access = access;
+    machine::save_machine(&machine);
+    log!("Set access for machine {}: {} user(s)", machine_name, machine.access.users.len());
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+use crate::*;
+use crate::settings::Key;
+
+/// Installed location of the config tree, used when the data-dir setting is not set.
+pub const DEFAULT_DATA_DIR: &str = "/usr/share/reflectron";
+
+/// The config tree shipped in the binary, used when no data directory is installed.
+/// Entries are (distribution, path inside the image, contents). A test checks that they
+/// match files/.
+const EMBEDDED: &[(&str, &str, &str)] = &[
+    ("debian", "/etc/dkms/zfs.conf", include_str!("../files/debian/etc/dkms/zfs.conf")),
+    ("debian", "/etc/initramfs-tools/conf.d/umask.conf", include_str!("../files/debian/etc/initramfs-tools/conf.d/umask.conf")),
+    ("ubuntu", "/etc/initramfs-tools/conf.d/umask.conf", include_str!("../files/ubuntu/etc/initramfs-tools/conf.d/umask.conf")),
+];
+
+
+/// The data directory to read the config tree from: the data-dir setting, else the installed
+/// default if it exists. None means the embedded tree is used.
+pub fn data_dir() -> Option<String> {
+    match settings::get(Key::DataDir) {
+        Some(dir) => {
+            if !Path::new(&dir).join("files").is_dir() {
+                halt!("Data directory {} has no files directory. Use 'ref set data-dir <dir>' to change it.", dir);
+            }
+            Some(dir)
+        }
+        None => Path::new(DEFAULT_DATA_DIR).join("files").is_dir().then(|| DEFAULT_DATA_DIR.to_owned()),
+    }
+}
+
+
+fn walk(root: &Path, dir: &Path, files: &mut Vec<(String, String)>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| halt!("Could not read config directory {:?}: {}", dir, e));
+    for entry in entries {
+        let path = entry.unwrap_or_else(|e| halt!("Could not read config directory {:?}: {}", dir, e)).path();
+        if path.is_dir() {
+            walk(root, &path, files);
+        } else {
+            let contents = fs::read_to_string(&path).unwrap_or_else(|e| halt!("Could not read config file {:?}: {}", path, e));
+            let dest = path.strip_prefix(root).unwrap_or_else(|_| halt!("Config file {:?} is outside {:?}", path, root));
+            files.push((format!("/{}", dest.to_string_lossy()), contents));
+        }
+    }
+}
+
+
+/// Config files installed into every image of `distro`, as (path inside the image, contents).
+pub fn config_files(distro: &str) -> Vec<(String, String)> {
+    match data_dir() {
+        Some(dir) => {
+            let root = Path::new(&dir).join("files").join(distro);
+            if !root.join("etc").is_dir() {
+                halt!("Data directory {} has no config for {}: {:?} does not exist", dir, distro, root.join("etc"));
+            }
+            let mut files = Vec::new();
+            walk(&root, &root.join("etc"), &mut files);
+            files.sort();
+            files
+        }
+        None => EMBEDDED.iter()
+            .filter(|(d, _, _)| *d == distro)
+            .map(|(_, path, contents)| (path.to_string(), contents.to_string()))
+            .collect(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_matches_files_directory() {
+        let files = Path::new(env!("CARGO_MANIFEST_DIR")).join("files");
+        let mut on_disk = Vec::new();
+        for entry in fs::read_dir(&files).unwrap() {
+            let root = entry.unwrap().path();
+            let distro = root.file_name().unwrap().to_string_lossy().into_owned();
+            let mut found = Vec::new();
+            walk(&root, &root.join("etc"), &mut found);
+            on_disk.extend(found.into_iter().map(|(path, contents)| (distro.clone(), path, contents)));
+        }
+        on_disk.sort();
+        let mut embedded: Vec<(String, String, String)> = EMBEDDED.iter()
+            .map(|(distro, path, contents)| (distro.to_string(), path.to_string(), contents.to_string()))
+            .collect();
+        embedded.sort();
+        assert_eq!(embedded, on_disk, "EMBEDDED in src/data.rs is out of step with files/");
+    }
+}
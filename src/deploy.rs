@@ -0,0 +1,676 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+use ssh2::Session;
+use crate::*;
+use crate::machine::Machine;
+use crate::overlay::ROOT_POOL;
+
+/// Where deployed roots are received on the production pool. Boot environments are cloned
+/// from its snapshots.
+pub const RECEIVE_DATASET: &str = "reflectron/root";
+const SNAPSHOT_PREFIX: &str = "deploy-";
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+
+/// A root snapshot sent to production.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Deployment {
+    /// Snapshot name, the same on the test VM's boot environment and in production
+    pub snapshot: String,
+    pub guid: String,
+    /// Boot environment of the test VM the snapshot was taken of
+    pub source: String,
+    /// Snapshot the send was incremental from, if any
+    pub incremental_from: Option<String>,
+    pub raw: bool,
+    pub started: String,
+    pub finished: String,
+    /// sha256 of the machine's overlay files and checked files in the snapshot, keyed by path
+    #[serde(default)]
+    pub checksums: BTreeMap<String, String>,
+    /// Of those files, the ones that could not be checksummed
+    #[serde(default)]
+    pub skipped_files: Vec<String>,
+    /// Deployed boot environments 'ref activate' keeps, if the deploy was applied from a plan
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
+/// A deploy prepared for review: its snapshot is taken on the test VM, and what it will change
+/// in production is worked out, but production is not touched until the plan is applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Plan {
+    pub machine: String,
+    pub created: String,
+    pub source: String,
+    pub snapshot: String,
+    pub guid: String,
+    pub incremental_from: Option<String>,
+    pub raw: bool,
+    /// Size of the send stream, from a dry run
+    pub estimated_bytes: Option<u64>,
+    /// Datasets the deploy creates in production. It never destroys any, as it receives
+    /// without -F.
+    pub create_datasets: Vec<String>,
+    /// What 'ref activate' will then change
+    pub activation: ActivationPlan,
+    /// Production as the plan found it
+    pub production: Production,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActivationPlan {
+    pub create_environment: String,
+    pub default_before: Option<String>,
+    /// Deployed boot environments to keep, which 'ref activate' uses unless given another
+    pub keep: usize,
+    pub destroy_environments: Vec<String>,
+    pub create_datasets: Vec<String>,
+}
+
+/// The facts about production a plan depends on. Applying the plan refuses to run if any of
+/// them changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Production {
+    pub address: String,
+    /// Snapshots of the receive dataset, oldest first
+    pub snapshots: Vec<Snapshot>,
+    pub interrupted_receive: bool,
+    pub datasets: Vec<String>,
+    pub default_environment: Option<String>,
+    pub running_environment: Option<String>,
+    pub environments: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub guid: String,
+}
+
+/// A deploy whose snapshot has been taken but has not yet been confirmed in production. It is
+/// kept until the transfer completes, so an interrupted deploy can be resumed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub snapshot: String,
+    pub guid: String,
+    pub source: String,
+    pub incremental_from: Option<String>,
+    pub raw: bool,
+    pub started: String,
+    pub attempts: u32,
+    /// From the plan the deploy was applied from, recorded on its deployment
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
+
+fn deployments_db() -> sled::Tree {
+    database().open_tree("deployments").unwrap_or_else(|e| halt!("Could not open deployments database tree: {}", e))
+}
+
+/// All deployments of the machine, oldest first.
+pub fn deployments(machine_name: &str) -> Vec<Deployment> {
+    deployments_db().get(machine_name.as_bytes())
+        .unwrap_or_else(|e| halt!("Could not retreive deployments of {} : {}", machine_name, e))
+        .and_then(|bytes| ron::from_str(&String::from_utf8_lossy(&bytes)).ok())
+        .unwrap_or_default()
+}
+
+fn save_deployments(machine_name: &str, deployments: &[Deployment]) {
+    let data = ron::to_string(deployments).unwrap_or_else(|e| halt!("Could not serialize deployments: {}", e));
+    let db = deployments_db();
+    db.insert(machine_name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+fn transfers_db() -> sled::Tree {
+    database().open_tree("transfers").unwrap_or_else(|e| halt!("Could not open transfers database tree: {}", e))
+}
+
+pub fn pending_transfer(machine_name: &str) -> Option<Transfer> {
+    transfers_db().get(machine_name.as_bytes())
+        .unwrap_or_else(|e| halt!("Could not retreive transfer of {} : {}", machine_name, e))
+        .and_then(|bytes| ron::from_str(&String::from_utf8_lossy(&bytes)).ok())
+}
+
+fn save_transfer(machine_name: &str, transfer: Option<&Transfer>) {
+    let db = transfers_db();
+    match transfer {
+        Some(transfer) => {
+            let data = ron::to_string(transfer).unwrap_or_else(|e| halt!("Could not serialize transfer: {}", e));
+            db.insert(machine_name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+        }
+        None => {
+            db.remove(machine_name.as_bytes()).unwrap_or_else(|e| halt!("Could not remove transfer of {}: {}", machine_name, e));
+        }
+    }
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+pub fn last_deployment(machine_name: &str) -> Option<Deployment> {
+    deployments(machine_name).pop()
+}
+
+
+/// The dataset production receives into, e.g. rpool/reflectron/root.
+pub fn receive_dataset() -> String {
+    format!("{}/{}", ROOT_POOL, RECEIVE_DATASET)
+}
+
+/// The boot environment the test VM boots, from its imported pool.
+pub fn source_environment(machine: &Machine) -> String {
+    let bootfs = get(zpool_query(&["get", "-H", "-o", "value", "bootfs", &install::host_pool(&machine.name)])).trim().to_owned();
+    if bootfs.is_empty() || bootfs == "-" {
+        halt!("The root pool of {} has no boot environment set. Run 'ref install {}' first.", machine.name, machine.name);
+    }
+    bootfs
+}
+
+fn guid(snapshot: &str) -> String {
+    get(zfs_query(&["get", "-H", "-o", "value", "guid", snapshot])).trim().to_owned()
+}
+
+fn snapshot_exists(snapshot: &str) -> bool {
+    let mut command = zfs_query(&["list", "-H", "-t", "snapshot", snapshot]);
+    command.stderr(Stdio::null());
+    success_stauts(command)
+}
+
+
+/// The guid of a snapshot in production, if it exists.
+pub fn remote_guid(sess: &Session, address: &str, snapshot: &str) -> Option<String> {
+    let output = machine::remote_run(sess, address, &format!("Look up {}", snapshot), &format!(
+        "zfs get -H -o value guid {} 2>/dev/null || true", snapshot
+    ));
+    let guid = output.trim();
+    (!guid.is_empty()).then(|| guid.to_owned())
+}
+
+
+/// Counts what passes through and prints the throughput, and the time left when the size of
+/// the stream is known.
+struct Progress<R> {
+    inner: R,
+    bytes: u64,
+    total: Option<u64>,
+    started: Instant,
+    reported: Instant,
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.bytes += count as u64;
+        if self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+            self.reported = Instant::now();
+        }
+        Ok(count)
+    }
+}
+
+impl<R> Progress<R> {
+    fn rate(&self) -> f64 {
+        self.bytes as f64 / self.started.elapsed().as_secs_f64().max(0.001)
+    }
+
+    fn report(&self) {
+        let rate = self.rate();
+        match self.total {
+            Some(total) if total > self.bytes && rate > 0.0 => {
+                let left = ((total - self.bytes) as f64 / rate) as u64;
+                println!(
+                    "{} of {} sent, {}/s, {}:{:02}:{:02} left",
+                    mib(self.bytes), mib(total), mib(rate as u64), left / 3600, left / 60 % 60, left % 60
+                );
+            }
+            _ => println!("{} sent, {}/s", mib(self.bytes), mib(rate as u64)),
+        }
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1048576.0)
+}
+
+
+/// Stream `send` into `receive` run on the remote machine over the SSH session. `size` is the
+/// expected size of the stream, for progress reports.
+pub fn send_remote(description: &str, mut send: std::process::Command, size: Option<u64>, sess: &Session, address: &str, receive: &str) {
+    send.stdout(Stdio::piped());
+    let mut child = send.spawn().unwrap_or_else(|e| halt!("Failed to spawn command '{}': {}", description, e));
+    let stream = child.stdout.take().expect("Failed to capture stdout");
+    let mut progress = Progress { inner: stream, bytes: 0, total: size, started: Instant::now(), reported: Instant::now() };
+
+    let mut channel = sess.channel_session().unwrap_or_else(|e| halt!("Could not open SSH channel to {} : {}", address, e));
+    channel.exec(receive).unwrap_or_else(|e| halt!("Could not run '{}' on {}: {}", receive, address, e));
+    let copied = io::copy(&mut progress, &mut channel);
+    channel.send_eof().unwrap_or_else(|e| halt!("Could not finish stream to {}: {}", address, e));
+
+    let (output, errors) = machine::read_outputs(sess, &mut channel).unwrap_or_default();
+    channel.wait_close().unwrap_or_else(|e| halt!("Could not close SSH channel to {}: {}", address, e));
+    let receive_status = channel.exit_status().unwrap_or_else(|e| halt!("Could not get exit status of '{}' on {}: {}", receive, address, e));
+    let send_status = child.wait().unwrap_or_else(|e| halt!("Failed to wait for send: {}", e));
+
+    if receive_status != 0 {
+        halt!("'{}' failed on {} with exit code {}:\n{}{}", receive, address, receive_status, output, errors);
+    }
+    copied.unwrap_or_else(|e| halt!("Stream to {} failed: {}", address, e));
+    if !send_status.success() {
+        halt!("{} failed with exit code: {:?}", description, send_status.code());
+    }
+    log!("{} succeeded, {} sent at {}/s.", description, mib(progress.bytes), mib(progress.rate() as u64));
+}
+
+
+/// The size of the stream `zfs send` would produce with these options, from a dry run.
+fn estimate_size(options: &[&str]) -> Option<u64> {
+    let mut args = vec!["send", "-nP"];
+    args.extend_from_slice(options);
+    get(zfs(&args)).lines()
+        .find(|line| line.starts_with("size"))
+        .and_then(|line| line.split_whitespace().last())
+        .and_then(|size| size.parse().ok())
+}
+
+/// The token to resume an interrupted receive into `dataset` in production with, if any.
+fn resume_token(sess: &Session, address: &str, dataset: &str) -> Option<String> {
+    let output = machine::remote_run(sess, address, "Check for an interrupted receive", &format!(
+        "zfs get -H -o value receive_resume_token {} 2>/dev/null || true", dataset
+    ));
+    let token = output.trim();
+    (!token.is_empty() && token != "-").then(|| token.to_owned())
+}
+
+
+/// Send the transfer's snapshot to production, resuming from where an earlier attempt was
+/// interrupted if production kept the partial stream.
+fn transfer(machine: &Machine, transfer: &mut Transfer, sess: &Session, address: &str) {
+    let target = receive_dataset();
+    let snapshot = format!("{}@{}", transfer.source, transfer.snapshot);
+    let mut options = Vec::new();
+    match resume_token(sess, address, &target) {
+        Some(token) => {
+            log!("Resuming interrupted send of {} to {}", snapshot, machine.name);
+            options.push("-t".to_owned());
+            options.push(token);
+        }
+        None => options = send_options(&transfer.source, &transfer.snapshot, transfer.incremental_from.as_deref(), transfer.raw),
+    }
+    let options: Vec<&str> = options.iter().map(|o| o.as_str()).collect();
+    let size = estimate_size(&options);
+
+    transfer.attempts += 1;
+    save_transfer(&machine.name, Some(transfer));
+
+    let mut args = vec!["send"];
+    args.extend_from_slice(&options);
+    send_remote(
+        &format!("Send {} to {}", snapshot, machine.name),
+        zfs(&args),
+        size,
+        sess,
+        address,
+        &format!("zfs receive -s -u -o canmount=noauto -o mountpoint=/ {}", target)
+    );
+}
+
+
+/// Check that production has the transfer's snapshot and record the deployment.
+fn conclude(machine: &Machine, transfer: Transfer, sess: &Session, address: &str) {
+    let received = format!("{}@{}", receive_dataset(), transfer.snapshot);
+    match remote_guid(sess, address, &received) {
+        Some(remote) if remote == transfer.guid => {}
+        Some(remote) => halt!("Snapshot {} on {} has guid {}, but {} was sent", received, machine.name, remote, transfer.guid),
+        None => halt!("Snapshot {} did not arrive on {}", received, machine.name),
+    }
+    let (checksums, skipped_files) = drift::snapshot_checksums(machine, &transfer.source, &transfer.snapshot);
+    install::export(machine);
+
+    let mut deployments = deployments(&machine.name);
+    deployments.push(Deployment {
+        snapshot: transfer.snapshot,
+        guid: transfer.guid,
+        source: transfer.source,
+        incremental_from: transfer.incremental_from,
+        raw: transfer.raw,
+        started: transfer.started,
+        finished: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        checksums,
+        skipped_files,
+        keep: transfer.keep,
+    });
+    save_deployments(&machine.name, &deployments);
+    save_transfer(&machine.name, None);
+    log!("Deployed {} to {} as {}", machine.name, address, received);
+}
+
+
+/// The snapshot to send incrementally from: the last one deployed, which both the test VM and
+/// production must still have unchanged. None for the first deploy.
+fn incremental_base(machine: &Machine, source: &str, sess: &Session, address: &str) -> Option<String> {
+    let target = receive_dataset();
+    match last_deployment(&machine.name) {
+        Some(previous) => {
+            let local = format!("{}@{}", source, previous.snapshot);
+            if previous.source != source || !snapshot_exists(&local) {
+                halt!(
+                    "The last deployed snapshot {} of {} is no longer on the test VM's boot environment {}, so an incremental send is not possible.",
+                    previous.snapshot, machine.name, source
+                );
+            }
+            let remote = format!("{}@{}", target, previous.snapshot);
+            match remote_guid(sess, address, &remote) {
+                Some(guid) if guid == previous.guid => Some(previous.snapshot),
+                Some(_) => halt!("Snapshot {} on {} does not match the one deployed. Check production for changes.", remote, machine.name),
+                None => halt!("Snapshot {} is missing on {}. Check production for changes.", remote, machine.name),
+            }
+        }
+        None => {
+            if remote_guid(sess, address, &target).is_some() {
+                halt!("{} already exists on {}, but nothing has been deployed to it by reflectron", target, machine.name);
+            }
+            None
+        }
+    }
+}
+
+/// Snapshot the test VM's boot environment for a deploy, returning the snapshot's name and guid.
+/// The machine's overlay is rendered and copied in first, so changes to its access, state or
+/// templates since it was installed are deployed too.
+fn take_snapshot(machine: &Machine, source: &str) -> (String, String) {
+    install::copy_overlay(machine, source);
+    let name = format!("{}{}", SNAPSHOT_PREFIX, Local::now().format("%Y%m%dT%H%M%S"));
+    let snapshot = format!("{}@{}", source, name);
+    perform(&format!("Snapshot {}", snapshot), None, zfs(&["snapshot", &snapshot]), false);
+    let guid = guid(&snapshot);
+    (name, guid)
+}
+
+fn create_parent(sess: &Session, address: &str) {
+    machine::remote_run(sess, address, "Create receive dataset parent", &format!(
+        "zfs list {0}/reflectron >/dev/null 2>&1 || zfs create -o canmount=off -o mountpoint=none {0}/reflectron", ROOT_POOL
+    ));
+}
+
+/// The options of the full or incremental send of a snapshot of the test VM's boot environment.
+fn send_options(source: &str, snapshot: &str, base: Option<&str>, raw: bool) -> Vec<String> {
+    let mut options = Vec::new();
+    if raw {
+        options.push("-w".to_owned());
+    }
+    if let Some(base) = base {
+        options.push("-i".to_owned());
+        options.push(format!("@{}", base));
+    }
+    options.push(format!("{}@{}", source, snapshot));
+    options
+}
+
+
+/// Snapshot the test VM's boot environment and replicate it to production, incrementally from
+/// the last deployed snapshot when both sides still have it. The VM must be stopped.
+/// An interrupted deploy is continued with `resume`, or discarded with `abort`.
+pub fn deploy(machine_name: &str, resume: bool, abort: bool) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let pending = pending_transfer(machine_name);
+    match (&pending, resume || abort) {
+        (Some(pending), false) => halt!(
+            "The deploy of {} to {} was interrupted after {} attempt(s). Continue it with 'ref deploy {} --resume', or discard it with 'ref deploy {} --abort'.",
+            pending.snapshot, machine_name, pending.attempts, machine_name, machine_name
+        ),
+        (None, true) => halt!("There is no interrupted deploy of {}", machine_name),
+        _ => {}
+    }
+
+    if abort {
+        let (sess, address) = machine::session(&machine);
+        if resume_token(&sess, &address, &receive_dataset()).is_some() {
+            machine::remote_run(&sess, &address, "Discard partial receive", &format!("zfs receive -A {}", receive_dataset()));
+        }
+        save_transfer(machine_name, None);
+        log!("Discarded the interrupted deploy of {}", machine_name);
+        return;
+    }
+
+    install::import(&machine);
+    let (sess, address) = machine::session(&machine);
+    if let Some(mut pending) = pending {
+        let received = format!("{}@{}", receive_dataset(), pending.snapshot);
+        if remote_guid(&sess, &address, &received).is_none() {
+            transfer(&machine, &mut pending, &sess, &address);
+        }
+        conclude(&machine, pending, &sess, &address);
+        return;
+    }
+
+    let source = source_environment(&machine);
+    let base = incremental_base(&machine, &source, &sess, &address);
+    let (name, guid) = take_snapshot(&machine, &source);
+    create_parent(&sess, &address);
+    let mut pending = Transfer {
+        snapshot: name,
+        guid,
+        source,
+        incremental_from: base,
+        raw: keys::raw_send(&machine),
+        started: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        attempts: 0,
+        keep: None,
+    };
+    transfer(&machine, &mut pending, &sess, &address);
+    conclude(&machine, pending, &sess, &address);
+}
+
+
+/// What production looks like to a deploy plan.
+fn production(sess: &Session, address: &str) -> Production {
+    let target = receive_dataset();
+    let snapshots = machine::remote_run(sess, address, "List deployed snapshots", &format!(
+        "zfs list -H -t snapshot -o name,guid -s creation {} 2>/dev/null || true", target
+    ))
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, guid)| Snapshot {
+            name: name.rsplit_once('@').map(|(_, s)| s).unwrap_or(name).to_owned(),
+            guid: guid.trim().to_owned(),
+        })
+        .collect();
+    let datasets = machine::remote_run(sess, address, "List reflectron datasets", &format!(
+        "zfs list -H -o name -r {}/reflectron 2>/dev/null || true", ROOT_POOL
+    ))
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect();
+    Production {
+        address: address.to_owned(),
+        snapshots,
+        interrupted_receive: resume_token(sess, address, &target).is_some(),
+        datasets,
+        default_environment: environment::current(sess, address),
+        running_environment: environment::running(sess, address),
+        environments: environment::environments(sess, address),
+    }
+}
+
+
+/// Prepare a deploy of the machine and save it to `file` for review, without touching
+/// production. The test VM's boot environment is snapshotted now, so the plan applies exactly
+/// what was reviewed. Activating the deploy keeps `keep` deployed boot environments.
+pub fn plan(machine_name: &str, file: &str, keep: usize) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    if keep == 0 {
+        halt!("At least one boot environment has to be kept");
+    }
+    if let Some(pending) = pending_transfer(machine_name) {
+        halt!("The deploy of {} to {} was interrupted. Resume or abort it before planning another.", pending.snapshot, machine_name);
+    }
+    let (sess, address) = machine::session(&machine);
+    let production = production(&sess, &address);
+    if production.interrupted_receive {
+        halt!("{} has a partially received stream in {}. Discard it with 'zfs receive -A {}' on {} first.", machine_name, receive_dataset(), receive_dataset(), machine_name);
+    }
+    install::import(&machine);
+
+    let source = source_environment(&machine);
+    let base = incremental_base(&machine, &source, &sess, &address);
+    let raw = keys::raw_send(&machine);
+    let (snapshot, guid) = take_snapshot(&machine, &source);
+    let options = send_options(&source, &snapshot, base.as_deref(), raw);
+    let estimated_bytes = estimate_size(&options.iter().map(|o| o.as_str()).collect::<Vec<_>>());
+    install::export(&machine);
+
+    let mut create_datasets = Vec::new();
+    for dataset in [format!("{}/reflectron", ROOT_POOL), receive_dataset()] {
+        if !production.datasets.contains(&dataset) {
+            create_datasets.push(dataset);
+        }
+    }
+    let mut environments = production.environments.clone();
+    environments.push(snapshot.clone());
+    let activation = ActivationPlan {
+        create_environment: environment::environment_dataset(&snapshot),
+        default_before: production.default_environment.clone(),
+        keep,
+        destroy_environments: environment::prunable(&environments, &[Some(snapshot.clone()), production.running_environment.clone()], keep)
+            .iter().map(|e| environment::environment_dataset(e)).collect(),
+        create_datasets: state::remote_datasets(&machine).into_iter().filter(|d| !production.datasets.contains(d)).collect(),
+    };
+
+    let plan = Plan {
+        machine: machine_name.to_owned(),
+        created: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        source,
+        snapshot,
+        guid,
+        incremental_from: base,
+        raw,
+        estimated_bytes,
+        create_datasets,
+        activation,
+        production,
+    };
+    let data = serde_yaml::to_string(&plan).unwrap_or_else(|e| halt!("Could not serialize plan: {}", e));
+    fs::write(file, data).unwrap_or_else(|e| halt!("Could not write plan {}: {}", file, e));
+    print_plan(&plan);
+    println!("The snapshot {}@{} is kept on the test VM, whether or not the plan is applied.", plan.source, plan.snapshot);
+    log!("Saved the deploy plan of {} to {}. Apply it with 'ref deploy {} --apply {}'.", machine_name, file, machine_name, file);
+}
+
+fn print_plan(plan: &Plan) {
+    println!("Deploy {}@{} to {} ({})", plan.source, plan.snapshot, plan.machine, plan.production.address);
+    println!("  Send:    {}{}", plan.incremental_from.as_ref().map(|b| format!("incremental from {}", b)).unwrap_or("full".to_owned()), if plan.raw { ", raw" } else { "" });
+    println!("  Size:    {}", plan.estimated_bytes.map(mib).unwrap_or("unknown".to_owned()));
+    println!("  Receive: {}@{}", receive_dataset(), plan.snapshot);
+    for dataset in &plan.create_datasets {
+        println!("  Create:  {}", dataset);
+    }
+    println!("Then 'ref activate {}' will", plan.machine);
+    println!("  Create:  {} (read-only)", plan.activation.create_environment);
+    println!("  Default: {} -> {}", plan.activation.default_before.as_deref().unwrap_or("none"), plan.snapshot);
+    for dataset in &plan.activation.create_datasets {
+        println!("  Create:  {}", dataset);
+    }
+    for environment in &plan.activation.destroy_environments {
+        println!("  Destroy: {}", environment);
+    }
+    println!("  Keep:    {} deployed boot environments", plan.activation.keep);
+}
+
+
+/// Carry out a saved deploy plan, if neither production nor the test VM changed since it was made.
+pub fn apply(machine_name: &str, file: &str) {
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| halt!("Could not read plan {}: {}", file, e));
+    let plan: Plan = serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse plan {}: {}", file, e));
+    if plan.machine != machine_name {
+        halt!("Plan {} is for machine {}, not {}", file, plan.machine, machine_name);
+    }
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    if pending_transfer(machine_name).is_some() {
+        halt!("A deploy of {} was interrupted since the plan was made. Resume or abort it, and plan again.", machine_name);
+    }
+    if last_deployment(machine_name).map(|d| d.snapshot) != plan.incremental_from {
+        halt!("{} has been deployed to since plan {} was made. Plan again.", machine_name, file);
+    }
+    if keys::raw_send(&machine) != plan.raw {
+        halt!("The encryption of {} changed since plan {} was made. Plan again.", machine_name, file);
+    }
+
+    install::import(&machine);
+    let snapshot = format!("{}@{}", plan.source, plan.snapshot);
+    if !snapshot_exists(&snapshot) || guid(&snapshot) != plan.guid {
+        halt!("Snapshot {} of the plan is gone or was replaced. Plan again.", snapshot);
+    }
+    let (sess, address) = machine::session(&machine);
+    let current = production(&sess, &address);
+    let changes: Vec<&str> = [
+        (current.address != plan.production.address, "address"),
+        (current.snapshots != plan.production.snapshots, "deployed snapshots"),
+        (current.interrupted_receive != plan.production.interrupted_receive, "interrupted receive"),
+        (current.datasets != plan.production.datasets, "datasets"),
+        (current.default_environment != plan.production.default_environment, "default boot environment"),
+        (current.running_environment != plan.production.running_environment, "running boot environment"),
+        (current.environments != plan.production.environments, "boot environments"),
+    ].iter().filter(|(changed, _)| *changed).map(|(_, what)| *what).collect();
+    if !changes.is_empty() {
+        halt!("Production {} changed since plan {} was made: {}. Plan again.", machine_name, file, changes.join(", "));
+    }
+
+    create_parent(&sess, &address);
+    let mut pending = Transfer {
+        snapshot: plan.snapshot,
+        guid: plan.guid,
+        source: plan.source,
+        incremental_from: plan.incremental_from,
+        raw: plan.raw,
+        started: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        attempts: 0,
+        keep: Some(plan.activation.keep),
+    };
+    transfer(&machine, &mut pending, &sess, &address);
+    conclude(&machine, pending, &sess, &address);
+}
+
+
+pub fn print_deployments(machine_name: &str) {
+    let deployments = deployments(machine_name);
+    if deployments.is_empty() {
+        println!("Nothing has been deployed to {}", machine_name);
+    }
+    for deployment in deployments {
+        println!(
+            "{} {} {}{} (guid {})",
+            deployment.finished,
+            deployment.snapshot,
+            deployment.incremental_from.map(|b| format!("incremental from {}", b)).unwrap_or("full".to_owned()),
+            if deployment.raw { ", raw" } else { "" },
+            deployment.guid
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_options_for_each_kind_of_send() {
+        let cases = [
+            (None, false, vec!["r-web1/ROOT/web@deploy-2"]),
+            (None, true, vec!["-w", "r-web1/ROOT/web@deploy-2"]),
+            (Some("deploy-1"), false, vec!["-i", "@deploy-1", "r-web1/ROOT/web@deploy-2"]),
+            (Some("deploy-1"), true, vec!["-w", "-i", "@deploy-1", "r-web1/ROOT/web@deploy-2"]),
+        ];
+        for (base, raw, expected) in cases {
+            assert_eq!(send_options("r-web1/ROOT/web", "deploy-2", base, raw), expected, "{:?} {}", base, raw);
+        }
+    }
+}
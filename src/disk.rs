@@ -2,7 +2,6 @@ use crate::*;
 use std::fmt;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
-use settings::Key;
 use crate::machine::Machine;
 
 pub const DISK_INFO: &str = "
@@ -66,7 +65,7 @@ pub fn parse_output(output: &str) -> Vec<Disk> {
     let mut lines = output.lines();
     let mut current_disk_name: Option<String> = None;
     
-    while let Some(line) = lines.next() {
+    for line in lines.by_ref() {
         if line.is_empty() {
             continue;
         }
@@ -161,9 +160,7 @@ pub fn create_zvols(machine: &Machine) {
         ).replace(" ", "-")
          .to_lowercase();
 
-        let zpool = settings::get(Key::DiskPool).unwrap_or_else(|| halt!("Reflectron property disk-pool has not been set. Use 'ref set disk-pool <poolname>' to set it, and retry this command."));
-        // Construct the full ZVOL path
-        let zvol_path = format!("{}/reflectron/{}/{}", zpool, machine.name, &create_disk_id(disk));
+        let zvol_path = zvol_dataset(machine, disk);
 
         // Create the ZVOL
         perform(
@@ -179,4 +176,9 @@ pub fn create_zvols(machine: &Machine) {
             true
         );
     }
-}
\ No newline at end of file
+}
+
+
+pub fn zvol_dataset(machine: &Machine, disk: &Disk) -> String {
+    format!("{}/reflectron/{}/{}", settings::disk_pool(), machine.name, &create_disk_id(disk))
+}
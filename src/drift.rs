@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fs;
+use serde::Serialize;
+use ssh2::Session;
+use crate::*;
+use crate::image::manifest::{self, sha256_file};
+use crate::machine::Machine;
+
+/// Exit code of 'ref drift' when production diverged, distinct from the 1 of a failed check.
+pub const DRIFT_EXIT_CODE: i32 = 2;
+
+
+/// How production diverged from what was last deployed to it.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub machine: String,
+    pub deployed: String,
+    pub checked_files: usize,
+    /// Files that were meant to be checked but could not be checksummed, at deploy time or now
+    pub skipped_files: Vec<String>,
+    pub findings: Vec<Finding>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Finding {
+    /// root, environment, state, overlay or file
+    pub check: String,
+    pub subject: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl Report {
+    fn add(&mut self, check: &str, subject: &str, expected: &str, found: &str) {
+        self.findings.push(Finding {
+            check: check.to_owned(),
+            subject: subject.to_owned(),
+            expected: expected.to_owned(),
+            found: found.to_owned(),
+        });
+    }
+}
+
+
+/// The files 'ref drift' checks: those of the machine's overlay, and the state file's checked
+/// files.
+fn checked_files(machine: &Machine) -> Vec<String> {
+    let overlay = overlay::overlay_path(&machine.name);
+    let mut files = Vec::new();
+    let mut pending = vec![overlay.clone()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path().to_string_lossy().into_owned();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => pending.push(path),
+                Ok(t) if t.is_file() => files.push(path[overlay.len()..].to_owned()),
+                _ => {}
+            }
+        }
+    }
+    files.extend(machine.state.checked_files.iter().cloned());
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Checksums of the checked files in a snapshot of the test VM's boot environment, read
+/// through its .zfs directory, along with the files that could not be checksummed. Files only
+/// root can read are checksummed through privileged sha256sum calls. The pool must be imported.
+pub fn snapshot_checksums(machine: &Machine, source: &str, snapshot: &str) -> (BTreeMap<String, String>, Vec<String>) {
+    let files = checked_files(machine);
+    if files.is_empty() {
+        return (BTreeMap::new(), Vec::new());
+    }
+    perform(&format!("Mount {}", source), None, zfs(&["mount", source]), false);
+    let root = format!("{}/.zfs/snapshot/{}", install::altroot(&machine.name), snapshot);
+    let mut checksums = BTreeMap::new();
+    let mut unreadable = Vec::new();
+    for file in &files {
+        match sha256_file(std::path::Path::new(&format!("{}{}", root, file))) {
+            Ok(sum) => { checksums.insert(file.clone(), sum); }
+            // pkexec arguments are split on whitespace
+            Err(_) if !file.contains(char::is_whitespace) => unreadable.push(format!("{}{}", root, file)),
+            Err(_) => {}
+        }
+    }
+    for chunk in unreadable.chunks(200) {
+        let mut args = vec![which("sha256sum"), "-z".to_owned()];
+        args.extend(chunk.iter().cloned());
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        // files missing from the snapshot fail, and are left out of the output
+        let output = pkexec(&args).output().unwrap_or_else(|e| halt!("Could not run sha256sum: {}", e));
+        for (sum, path) in manifest::parse_sha256sums(&String::from_utf8_lossy(&output.stdout)) {
+            checksums.insert(path[root.len()..].to_owned(), sum.to_owned());
+        }
+    }
+    perform(&format!("Unmount {}", source), None, zfs(&["umount", source]), false);
+
+    let skipped: Vec<String> = files.into_iter().filter(|f| !checksums.contains_key(f)).collect();
+    for file in &skipped {
+        log!("Not checking {} for drift: it could not be read in {}@{}", file, source, snapshot);
+    }
+    (checksums, skipped)
+}
+
+
+/// Run a check on production. Unlike machine::remote_run it prints nothing, which would end
+/// up in the JSON report.
+fn remote(sess: &Session, address: &str, description: &str, command: &str) -> String {
+    match machine::remote_status(sess, command) {
+        Some((0, output)) => output,
+        Some((status, output)) => halt!("{} failed on {} with exit code {}:\n{}", description, address, status, output),
+        None => halt!("{} failed on {}: connection lost", description, address),
+    }
+}
+
+/// Compare production with its last deployment: the received root's latest snapshot and
+/// anything written to it since, the running boot environment, the state datasets and overlay
+/// upper layers, and the checksums of the checked files.
+fn check(machine: &Machine, sess: &Session, address: &str) -> Report {
+    let deployment = deploy::last_deployment(&machine.name)
+        .unwrap_or_else(|| halt!("Nothing has been deployed to {}", machine.name));
+    let mut report = Report {
+        machine: machine.name.clone(),
+        deployed: deployment.snapshot.clone(),
+        checked_files: 0,
+        skipped_files: deployment.skipped_files.clone(),
+        findings: Vec::new(),
+    };
+
+    let target = deploy::receive_dataset();
+    let latest = remote(sess, address, "Get latest snapshot", &format!(
+        "zfs list -H -t snapshot -o name,guid -s creation {} 2>/dev/null | tail -n 1", target
+    ));
+    let expected = format!("{}@{} (guid {})", target, deployment.snapshot, deployment.guid);
+    match latest.trim().split_once('\t') {
+        Some((name, guid)) if *name == format!("{}@{}", target, deployment.snapshot) && guid.trim() == deployment.guid => {}
+        Some((name, guid)) => report.add("root", &target, &expected, &format!("{} (guid {})", name, guid.trim())),
+        None => report.add("root", &target, &expected, "no snapshot"),
+    }
+    let written = remote(sess, address, "Get bytes written to the root", &format!(
+        "zfs get -Hp -o value written {} 2>/dev/null || true", target
+    ));
+    if !matches!(written.trim(), "0" | "") {
+        report.add("root", &target, "nothing written since the last snapshot", &format!("{} bytes written", written.trim()));
+    }
+
+    let root = remote(sess, address, "Find running root", "findmnt -n -o SOURCE / || true");
+    let running = root.trim();
+    // only boot environments activated from deploys are read-only clones
+    if running.starts_with(&environment::environment_dataset("deploy-")) {
+        let dataset = running.to_owned();
+        let properties = remote(sess, address, "Get boot environment properties", &format!(
+            "zfs get -Hp -o value readonly,written {}", dataset
+        ));
+        let mut values = properties.lines().map(|v| v.trim());
+        if let Some(readonly) = values.next().filter(|r| *r != "on") {
+            report.add("environment", &dataset, "readonly=on", &format!("readonly={}", readonly));
+        }
+        if let Some(written) = values.next().filter(|w| *w != "0") {
+            report.add("environment", &dataset, "nothing written", &format!("{} bytes written", written));
+        }
+    }
+
+    let expected = state::remote_datasets(machine);
+    let container = format!("{}/{}", overlay::ROOT_POOL, state::STATE_CONTAINER);
+    let found: Vec<String> = remote(sess, address, "List state datasets", &format!(
+        "zfs list -H -o name -r {} 2>/dev/null || true", container
+    )).lines().map(|l| l.trim().to_owned()).filter(|l| !l.is_empty()).collect();
+    for dataset in expected.iter().filter(|d| !found.contains(d)) {
+        report.add("state", dataset, "present", "missing");
+    }
+    for dataset in found.iter().filter(|d| !expected.contains(d)) {
+        report.add("state", dataset, "not declared", "present");
+    }
+
+    for mount in machine.state.overlays.iter().filter(|o| !o.ignore_drift) {
+        let upper = format!("{}{}/upper", state::OVERLAY_STORE, mount.path);
+        let changed = remote(sess, address, &format!("List changes to {}", mount.path), &format!(
+            "find {} -mindepth 1 ! -type d 2>/dev/null || true", upper
+        ));
+        for file in changed.lines().filter_map(|l| l.trim().strip_prefix(&upper)) {
+            report.add("overlay", &format!("{}{}", mount.path, file), "as deployed", "changed in production");
+        }
+    }
+
+    // files are quoted for the remote shell
+    let (files, unquotable): (Vec<&String>, Vec<&String>) = deployment.checksums.keys().partition(|f| !f.contains('\''));
+    report.skipped_files.extend(unquotable.into_iter().cloned());
+    report.checked_files = files.len();
+    if !files.is_empty() {
+        let quoted: Vec<String> = files.iter().map(|f| format!("'{}'", f)).collect();
+        let output = remote(sess, address, "Checksum checked files", &format!("sha256sum -z -- {} 2>/dev/null || true", quoted.join(" ")));
+        let sums: BTreeMap<&str, &str> = manifest::parse_sha256sums(&output).into_iter()
+            .map(|(sum, file)| (file, sum))
+            .collect();
+        for file in files {
+            match sums.get(file.as_str()) {
+                Some(sum) if *sum == deployment.checksums[file] => {}
+                Some(sum) => report.add("file", file, &deployment.checksums[file], sum),
+                None => report.add("file", file, &deployment.checksums[file], "missing"),
+            }
+        }
+    }
+    report
+}
+
+
+/// Report how production diverged from its last deployment, as text or JSON, exiting with
+/// DRIFT_EXIT_CODE if it did.
+pub fn drift(machine_name: &str, json: bool) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let (sess, address) = machine::session(&machine);
+    let report = check(&machine, &sess, &address);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_else(|e| halt!("Could not serialize drift report: {}", e)));
+    } else {
+        if report.findings.is_empty() {
+            println!("{} matches deployment {} ({} files checked)", machine_name, report.deployed, report.checked_files);
+        } else {
+            println!("{} drifted from deployment {}:", machine_name, report.deployed);
+            for finding in &report.findings {
+                println!("  {:<12}{}: expected {}, found {}", finding.check, finding.subject, finding.expected, finding.found);
+            }
+        }
+        if !report.skipped_files.is_empty() {
+            println!("Not checked, as they could not be checksummed:");
+            for file in &report.skipped_files {
+                println!("  {}", file);
+            }
+        }
+    }
+    if !report.findings.is_empty() {
+        write_logfile(&format!("{} drifted from deployment {}: {} finding(s)", machine_name, report.deployed, report.findings.len()));
+        std::process::exit(DRIFT_EXIT_CODE);
+    }
+}
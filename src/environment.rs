@@ -0,0 +1,390 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+use ssh2::Session;
+use crate::*;
+use crate::machine::Machine;
+use crate::overlay::ROOT_POOL;
+
+pub const DEFAULT_KEEP: usize = 3;
+
+const REMOTE_BE_MOUNT: &str = "/tmp/reflectron-be";
+/// How long a host switched with kexec has to come back and report healthy.
+const KEXEC_TIMEOUT: Duration = Duration::from_secs(600);
+/// Seconds before a kernel that panicked while switching reboots, through ZFSBootMenu into
+/// the previous default.
+const KEXEC_PANIC_TIMEOUT: u32 = 10;
+/// Seconds after a switch before the new boot environment reboots itself into the previous
+/// default, unless its health checks passed and the watchdog was stopped.
+const KEXEC_WATCHDOG: u64 = 1200;
+const WATCHDOG_UNIT: &str = "reflectron-kexec-watchdog.service";
+/// Kernel argument arming the watchdog with its timeout
+const WATCHDOG_ARGUMENT: &str = "reflectron.watchdog";
+
+
+/// A change of the boot environment a production machine boots by default.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Activation {
+    /// "activate", "kexec" or "rollback"
+    pub action: String,
+    pub environment: String,
+    pub previous: Option<String>,
+    pub by: String,
+    pub at: String,
+}
+
+
+fn activations_db() -> sled::Tree {
+    database().open_tree("activations").unwrap_or_else(|e| halt!("Could not open activations database tree: {}", e))
+}
+
+pub fn activations(machine_name: &str) -> Vec<Activation> {
+    activations_db().get(machine_name.as_bytes())
+        .unwrap_or_else(|e| halt!("Could not retreive activations of {} : {}", machine_name, e))
+        .and_then(|bytes| ron::from_str(&String::from_utf8_lossy(&bytes)).ok())
+        .unwrap_or_default()
+}
+
+fn record(machine_name: &str, action: &str, environment: &str, previous: Option<String>) {
+    let mut activations = activations(machine_name);
+    activations.push(Activation {
+        action: action.to_owned(),
+        environment: environment.to_owned(),
+        previous,
+        by: std::env::var("SUDO_USER").or_else(|_| std::env::var("USER")).unwrap_or_else(|_| "unknown".to_owned()),
+        at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+    });
+    let data = ron::to_string(&activations).unwrap_or_else(|e| halt!("Could not serialize activations: {}", e));
+    let db = activations_db();
+    db.insert(machine_name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+
+fn container() -> String {
+    format!("{}/{}", ROOT_POOL, install::BOOT_CONTAINER)
+}
+
+pub fn environment_dataset(environment: &str) -> String {
+    format!("{}/{}", container(), environment)
+}
+
+/// The boot environment production boots by default, if set.
+pub fn current(sess: &Session, address: &str) -> Option<String> {
+    let bootfs = machine::remote_run(sess, address, "Get default boot environment", &format!(
+        "zpool get -H -o value bootfs {}", ROOT_POOL
+    ));
+    bootfs.trim().strip_prefix(&format!("{}/", container())).map(|e| e.to_owned())
+}
+
+/// Production's boot environments, oldest first.
+pub fn environments(sess: &Session, address: &str) -> Vec<String> {
+    machine::remote_run(sess, address, "List boot environments", &format!(
+        "zfs list -H -o name -s creation -d 1 {} 2>/dev/null || true", container()
+    ))
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(&format!("{}/", container())).map(|e| e.to_owned()))
+        .collect()
+}
+
+/// The boot environment production is running now, if it booted one.
+pub fn running(sess: &Session, address: &str) -> Option<String> {
+    let root = machine::remote_run(sess, address, "Find running root", "findmnt -n -o SOURCE / || true");
+    root.trim().strip_prefix(&format!("{}/", container())).map(|e| e.to_owned())
+}
+
+
+fn set_default(sess: &Session, address: &str, environment: &str) {
+    machine::remote_run(sess, address, &format!("Make {} the default boot environment", environment), &format!(
+        "zpool set bootfs={} {}", environment_dataset(environment), ROOT_POOL
+    ));
+}
+
+
+/// Make a deployed snapshot production's default boot environment: a read-only clone of the
+/// received snapshot under rpool/ROOT, where ZFSBootMenu finds it. The newest `keep` deployed
+/// environments are kept besides the running one, older ones are destroyed. Without `keep`,
+/// the number from the plan the snapshot was deployed from is used, or DEFAULT_KEEP.
+/// With `kexec`, production switches into it right away, and it only becomes the default once
+/// it has come back healthy.
+pub fn activate(machine_name: &str, snapshot: Option<&str>, keep: Option<usize>, kexec: bool) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let deployment = match snapshot {
+        Some(snapshot) => deploy::deployments(machine_name).into_iter().rev().find(|d| d.snapshot == snapshot)
+            .unwrap_or_else(|| halt!("Snapshot {} has not been deployed to {}. See 'ref deployments {}'.", snapshot, machine_name, machine_name)),
+        None => deploy::last_deployment(machine_name)
+            .unwrap_or_else(|| halt!("Nothing has been deployed to {}. Run 'ref deploy {}' first.", machine_name, machine_name)),
+    };
+    let keep = keep.or(deployment.keep).unwrap_or(DEFAULT_KEEP);
+    let snapshot = deployment.snapshot;
+    if keep == 0 {
+        halt!("At least one boot environment has to be kept");
+    }
+
+    let (sess, address) = machine::session(&machine);
+    let previous = current(&sess, &address);
+    if previous.as_deref() == Some(snapshot.as_str()) {
+        log!("{} is already the default boot environment of {}, skipping.", snapshot, machine_name);
+        return;
+    }
+
+    let dataset = environment_dataset(&snapshot);
+    machine::remote_run(&sess, &address, "Create boot environment container", &format!(
+        "zfs list {0} >/dev/null 2>&1 || zfs create -o canmount=off -o mountpoint=none {0}", container()
+    ));
+    if !environments(&sess, &address).contains(&snapshot) {
+        machine::remote_run(&sess, &address, &format!("Create boot environment {}", snapshot), &format!(
+            "zfs clone -o canmount=noauto -o mountpoint=/ -o readonly=on {}@{} {}",
+            deploy::receive_dataset(), snapshot, dataset
+        ));
+    }
+    zbm::set_remote_properties(&machine, &sess, &address);
+    state::create_remote(&machine, &sess, &address);
+    if !kexec {
+        set_default(&sess, &address, &snapshot);
+        record(machine_name, "activate", &snapshot, previous);
+        prune(&machine, &sess, &address, keep);
+        log!("{} will boot {} from its next restart", machine_name, snapshot);
+        return;
+    }
+
+    let sess = switch(&machine, sess, &address, &snapshot);
+    set_default(&sess, &address, &snapshot);
+    record(machine_name, "kexec", &snapshot, previous);
+    prune(&machine, &sess, &address, keep);
+    log!("{} is running {}", machine_name, snapshot);
+}
+
+
+/// Check that the boot environment can be switched into, and load its newest kernel and
+/// initramfs with kexec. Returns the kernel version loaded.
+fn load_kernel(machine: &Machine, sess: &Session, address: &str, environment: &str) -> String {
+    let dataset = environment_dataset(environment);
+    machine::remote_run(sess, address, "Check kexec is installed", "command -v kexec");
+
+    let encryption_root = machine::remote_run(sess, address, "Get encryption root", &format!(
+        "zfs get -H -o value encryptionroot {}", dataset
+    ));
+    let encryption_root = encryption_root.trim();
+    if encryption_root != "-" {
+        let status = machine::remote_run(sess, address, "Get key status", &format!(
+            "zfs get -H -o value keystatus {}", encryption_root
+        ));
+        if status.trim() != "available" {
+            let location = machine::remote_run(sess, address, "Get key location", &format!(
+                "zfs get -H -o value keylocation {}", encryption_root
+            ));
+            if location.trim() == "prompt" {
+                halt!(
+                    "The key of {} on {} is not loaded and can only be typed at a prompt. Load it with 'zfs load-key {}' on {}, or reboot into {} instead.",
+                    encryption_root, machine.name, encryption_root, machine.name, environment
+                );
+            }
+            machine::remote_run(sess, address, &format!("Load key of {}", encryption_root), &format!("zfs load-key {}", encryption_root));
+        }
+    }
+
+    let commandline = format!(
+        "root=ZFS={} boot=zfs {} panic={} {}={}",
+        dataset, machine.boot.commandline, KEXEC_PANIC_TIMEOUT, WATCHDOG_ARGUMENT, KEXEC_WATCHDOG
+    );
+    zbm::check_commandline(&commandline);
+    let output = machine::remote_run(sess, address, &format!("Load kernel of {}", environment), &format!(
+        "set -e
+        mkdir -p {mount}
+        mount -t zfs -o ro,zfsutil {dataset} {mount}
+        trap 'umount {mount}' EXIT
+        test -f {mount}{units}/{watchdog} || {{ echo '{dataset} has no kexec watchdog. Deploy and activate it again.' >&2; exit 1; }}
+        kernel=$(ls -1v {mount}/boot/vmlinuz-* | tail -n 1)
+        version=${{kernel#{mount}/boot/vmlinuz-}}
+        test -f {mount}/boot/initrd.img-$version
+        kexec -l $kernel --initrd={mount}/boot/initrd.img-$version --command-line='{commandline}'
+        echo $version",
+        mount = REMOTE_BE_MOUNT, dataset = dataset, commandline = commandline, units = state::UNIT_DIR, watchdog = WATCHDOG_UNIT
+    ));
+    output.trim().to_owned()
+}
+
+
+/// Write the kexec watchdog into the machine's overlay, so every deployed boot environment has
+/// it. It only runs when a switch armed it on the kernel command line.
+pub fn render_watchdog(overlay: &str) {
+    overlay::write(overlay, &format!("{}/{}", state::UNIT_DIR, WATCHDOG_UNIT), format!(
+        "# Managed by reflectron\n\
+        [Unit]\nDescription=Reboot unless a kexec switch is confirmed healthy\nDefaultDependencies=no\nConditionKernelCommandLine={0}\n\n\
+        [Service]\nExecStart=/bin/sh -c 'for a in $$(cat /proc/cmdline); do case $$a in {0}=*) sleep $${{a#*=}} ;; esac; done; exec systemctl reboot'\n",
+        WATCHDOG_ARGUMENT
+    ).as_bytes());
+    let wants = format!("{}{}/sysinit.target.wants", overlay, state::UNIT_DIR);
+    fs::create_dir_all(&wants).unwrap_or_else(|e| halt!("Could not create overlay directory {}: {}", wants, e));
+    let link = format!("{}/{}", wants, WATCHDOG_UNIT);
+    symlink(format!("../{}", WATCHDOG_UNIT), &link).unwrap_or_else(|e| halt!("Could not enable unit {}: {}", link, e));
+}
+
+
+fn boot_id(sess: &Session) -> Option<String> {
+    machine::remote_status(sess, "cat /proc/sys/kernel/random/boot_id").map(|(_, id)| id.trim().to_owned())
+}
+
+/// Whether production came back running the boot environment, with every unit started.
+fn healthy(sess: &Session, environment: &str) -> bool {
+    let root = machine::remote_status(sess, "findmnt -n -o SOURCE /");
+    if root.is_none_or(|(_, source)| source.trim() != environment_dataset(environment)) {
+        log!("Root of the switched system is not {}", environment_dataset(environment));
+        return false;
+    }
+    match machine::remote_status(sess, "timeout 300 systemctl is-system-running --wait") {
+        Some((0, _)) => true,
+        Some((_, state)) => {
+            log!("System state after switching is {}", state.trim());
+            false
+        }
+        None => false,
+    }
+}
+
+/// Switch production into the boot environment with kexec, while the previous one stays the
+/// default. If production does not come back healthy, it is rebooted, which brings it back into
+/// the previous default through ZFSBootMenu. A kernel panic reboots the same way on its own, and
+/// the watchdog armed on the new kernel's command line does if the switch is never confirmed.
+/// Returns a session to the switched system.
+fn switch(machine: &Machine, sess: Session, address: &str, environment: &str) -> Session {
+    let version = load_kernel(machine, &sess, address, environment);
+    let before = boot_id(&sess).unwrap_or_else(|| halt!("Could not read boot id of {}", machine.name));
+    machine::remote_run(&sess, address, &format!("Switch {} into {} with kernel {}", machine.name, environment, version),
+        "nohup sh -c 'sleep 2; systemctl kexec' >/dev/null 2>&1 &"
+    );
+    drop(sess);
+
+    log!("Waiting for {} to come back at {}", machine.name, address);
+    let started = Instant::now();
+    let sess = loop {
+        thread::sleep(Duration::from_secs(5));
+        if let Some(sess) = machine::try_agent_session(address) {
+            if boot_id(&sess).is_some_and(|id| !id.is_empty() && id != before) {
+                break sess;
+            }
+        }
+        if started.elapsed() > KEXEC_TIMEOUT {
+            halt!(
+                "{} did not come back within {} seconds. It remains set to boot its previous boot environment, and its watchdog reboots it there {} seconds after the switch. If it hangs before the watchdog starts, power cycle it to fall back.",
+                machine.name, KEXEC_TIMEOUT.as_secs(), KEXEC_WATCHDOG
+            );
+        }
+    };
+
+    if !healthy(&sess, environment) {
+        machine::remote_status(&sess, "nohup sh -c 'sleep 2; systemctl reboot' >/dev/null 2>&1 &");
+        halt!("{} failed its health checks after switching to {}. It is rebooting into its previous boot environment.", machine.name, environment);
+    }
+    machine::remote_run(&sess, address, "Disarm kexec watchdog", &format!("systemctl stop {}", WATCHDOG_UNIT));
+    sess
+}
+
+
+/// The deployed boot environments of `environments`, oldest first, beyond the newest `keep`,
+/// except the `protected` default and running ones.
+pub fn prunable(environments: &[String], protected: &[Option<String>], keep: usize) -> Vec<String> {
+    let deployed: Vec<&String> = environments.iter().filter(|e| e.starts_with("deploy-")).collect();
+    let excess = deployed.len().saturating_sub(keep);
+    deployed.into_iter().take(excess)
+        .filter(|e| !protected.iter().any(|p| p.as_deref() == Some(e.as_str())))
+        .cloned()
+        .collect()
+}
+
+/// Destroy deployed boot environments beyond the newest `keep`, never the default or running one.
+fn prune(machine: &Machine, sess: &Session, address: &str, keep: usize) {
+    let protected = [current(sess, address), running(sess, address)];
+    for environment in prunable(&environments(sess, address), &protected, keep) {
+        machine::remote_run(sess, address, &format!("Destroy old boot environment {} of {}", environment, machine.name), &format!(
+            "zfs destroy {}", environment_dataset(&environment)
+        ));
+    }
+}
+
+
+/// Make an earlier boot environment production's default again: the one given, or the one
+/// before the current default.
+pub fn rollback(machine_name: &str, to: Option<&str>) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let (sess, address) = machine::session(&machine);
+    let environments = environments(&sess, &address);
+    let current = current(&sess, &address);
+
+    let target = match to {
+        Some(to) => to.to_owned(),
+        None => {
+            let position = current.as_ref().and_then(|c| environments.iter().position(|e| e == c))
+                .unwrap_or_else(|| halt!("{} has no default boot environment to roll back from", machine_name));
+            if position == 0 {
+                halt!("{} is the oldest boot environment of {}, there is nothing to roll back to", environments[0], machine_name);
+            }
+            environments[position - 1].clone()
+        }
+    };
+    if !environments.contains(&target) {
+        halt!("{} has no boot environment {}. It has: {}", machine_name, target, environments.join(", "));
+    }
+    if current.as_deref() == Some(target.as_str()) {
+        log!("{} is already the default boot environment of {}, skipping.", target, machine_name);
+        return;
+    }
+
+    set_default(&sess, &address, &target);
+    record(machine_name, "rollback", &target, current);
+    log!("{} will boot {} from its next restart", machine_name, target);
+}
+
+
+pub fn print_activations(machine_name: &str) {
+    let activations = activations(machine_name);
+    if activations.is_empty() {
+        println!("No boot environment of {} has been activated", machine_name);
+    }
+    for activation in activations {
+        println!(
+            "{} {} {} (was {}) by {}",
+            activation.at,
+            activation.action,
+            activation.environment,
+            activation.previous.as_deref().unwrap_or("none"),
+            activation.by
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn prunable_keeps_the_newest() {
+        let environments = names(&["web", "deploy-1", "deploy-2", "deploy-3", "deploy-4"]);
+        assert_eq!(prunable(&environments, &[], 2), names(&["deploy-1", "deploy-2"]));
+        assert_eq!(prunable(&environments, &[], 4), names(&[]));
+        assert_eq!(prunable(&environments, &[], 10), names(&[]));
+    }
+
+    #[test]
+    fn prunable_never_returns_other_environments() {
+        let environments = names(&["web", "deploy-1", "manual"]);
+        assert_eq!(prunable(&environments, &[], 0), names(&["deploy-1"]));
+    }
+
+    #[test]
+    fn prunable_spares_the_default_and_running() {
+        let environments = names(&["deploy-1", "deploy-2", "deploy-3", "deploy-4"]);
+        let protected = [Some("deploy-1".to_owned()), Some("deploy-2".to_owned())];
+        assert_eq!(prunable(&environments, &protected, 1), names(&["deploy-3"]));
+        assert_eq!(prunable(&environments, &[None, None], 1), names(&["deploy-1", "deploy-2", "deploy-3"]));
+    }
+}
@@ -1,42 +1,538 @@
+pub mod bootstrap;
 pub mod debian;
+pub mod diff;
+pub mod exec;
+pub mod manifest;
+pub mod recipe;
+pub mod ubuntu;
 
 use std::fs;
 use std::path::Path;
 use std::process::exit;
 use std::env;
+use std::io::Write;
+use chrono::Local;
 use crate::*;
+use crate::machine::Machine;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Serialize, Deserialize};
 
+const IMAGES_DIR: &str = "/opt/reflectron/images";
 
-pub fn check_and_create_image_dir(image_name: &str) -> String {
-    let base_path = "/opt/reflectron/images";
-    let image_path = format!("{}/{}", base_path, image_name);
+pub const DEFAULT_ARCH: &str = "amd64";
+pub const DEFAULT_LOCALE: &str = "en_US.UTF-8";
+pub const DEFAULT_TIMEZONE: &str = "Etc/UTC";
+pub const DEFAULT_KEYBOARD: &str = "us";
+pub const DEFAULT_CONSOLE_FONT: &str = "Fixed";
+
+/// Debian-style architecture names and the matching qemu-user-static emulator suffix.
+pub const ARCHITECTURES: &[(&str, &str)] = &[
+    ("amd64", "x86_64"),
+    ("arm64", "aarch64"),
+];
+
+
+/// Supported distributions, in the order they are listed to the user.
+pub const BUILDERS: &[&dyn ImageBuilder] = &[
+    &debian::Debian,
+    &ubuntu::Ubuntu,
+];
+
+pub fn builder(distro: &str) -> Option<&'static dyn ImageBuilder> {
+    BUILDERS.iter().copied().find(|b| b.distro() == distro.to_lowercase())
+}
+
+pub fn supported_distros() -> String {
+    BUILDERS.iter().map(|b| b.distro()).collect::<Vec<_>>().join(", ")
+}
+
+
+/// Build options as given on the command line or in a recipe. Anything left out is
+/// filled in from settings or the builder's defaults by `Options::new`.
+#[derive(Default)]
+pub struct Choices {
+    pub release: Option<String>,
+    pub arch: Option<String>,
+    pub mirror: Option<String>,
+    pub security_mirror: Option<String>,
+    pub components: Option<Vec<String>>,
+    pub snapshot: Option<String>,
+    pub bootstrapper: Option<String>,
+    pub locales: Option<Vec<String>>,
+    pub timezone: Option<String>,
+    pub keyboard: Option<String>,
+    pub console_font: Option<String>,
+    pub hostname: Option<String>,
+    pub backports: bool,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Options {
+    /// Image name, defaults to <distro>-<release>-<arch>
+    pub name: String,
+    pub distro: String,
+    pub release: String,
+    pub arch: String,
+    pub mirror: String,
+    /// Mirror for the security suite, when it is not the builder's default
+    #[serde(default)]
+    pub security_mirror: Option<String>,
+    pub components: Vec<String>,
+    pub backports: bool,
+    /// Archive snapshot timestamp (YYYYMMDDTHHMMSSZ) the package set is pinned to
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// Tool that creates the base system, see `bootstrap::BOOTSTRAPPERS`
+    #[serde(default = "default_bootstrapper")]
+    pub bootstrapper: String,
+    /// Locales to generate, the first is the default
+    #[serde(default = "default_locales")]
+    pub locales: Vec<String>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// XKB keyboard layout
+    #[serde(default = "default_keyboard")]
+    pub keyboard: String,
+    /// console-setup font face
+    #[serde(default = "default_console_font")]
+    pub console_font: String,
+    /// Hostname until a machine's overlay sets its own
+    #[serde(default)]
+    pub hostname: String,
+}
+
+fn default_bootstrapper() -> String {
+    bootstrap::DEFAULT_BOOTSTRAPPER.to_owned()
+}
+
+fn default_locales() -> Vec<String> {
+    vec![DEFAULT_LOCALE.to_owned()]
+}
+
+fn default_timezone() -> String {
+    DEFAULT_TIMEZONE.to_owned()
+}
+
+fn default_keyboard() -> String {
+    DEFAULT_KEYBOARD.to_owned()
+}
+
+fn default_console_font() -> String {
+    DEFAULT_CONSOLE_FONT.to_owned()
+}
+
+/// Whether `snapshot` is an archive snapshot timestamp like 20240101T000000Z.
+fn valid_snapshot(snapshot: &str) -> bool {
+    if snapshot.len() != 16 || !snapshot.is_ascii() {
+        return false;
+    }
+    let (date, time) = snapshot.split_at(8);
+    date.chars().all(|c| c.is_ascii_digit())
+        && time.starts_with('T') && time.ends_with('Z') && time[1..7].chars().all(|c| c.is_ascii_digit())
+}
+
+impl Options {
+    /// Options for `builder`. A pinned snapshot selects the snapshot archive, otherwise the
+    /// mirror comes from the distribution's mirror setting or the builder's default.
+    pub fn new(builder: &dyn ImageBuilder, choices: Choices) -> Options {
+        let arch = choices.arch.unwrap_or_else(|| DEFAULT_ARCH.to_owned());
+        let release = choices.release.unwrap_or_else(|| builder.default_release().to_owned());
+        let (snapshot_mirror, snapshot_security_mirror) = match &choices.snapshot {
+            Some(snapshot) => {
+                let (mirror, security_mirror) = builder.snapshot_mirrors(snapshot, &arch);
+                (Some(mirror), Some(security_mirror))
+            }
+            None => (None, None),
+        };
+
+        Options {
+            name: format!("{}-{}-{}", builder.distro(), release, arch),
+            distro: builder.distro().to_owned(),
+            release,
+            mirror: choices.mirror
+                .or(snapshot_mirror)
+                .or_else(|| settings::get(builder.mirror_key()))
+                .unwrap_or_else(|| builder.default_mirror(&arch).to_owned()),
+            security_mirror: choices.security_mirror.or(snapshot_security_mirror),
+            components: choices.components.unwrap_or_else(|| builder.default_components().iter().map(|c| c.to_string()).collect()),
+            arch,
+            backports: choices.backports,
+            snapshot: choices.snapshot,
+            bootstrapper: choices.bootstrapper.unwrap_or_else(default_bootstrapper),
+            locales: choices.locales.unwrap_or_else(default_locales),
+            timezone: choices.timezone.unwrap_or_else(default_timezone),
+            keyboard: choices.keyboard.unwrap_or_else(default_keyboard),
+            console_font: choices.console_font.unwrap_or_else(default_console_font),
+            hostname: choices.hostname.unwrap_or_else(|| builder.distro().to_owned()),
+        }
+    }
+
+    /// A mirror on the local filesystem, which has to be bind mounted into the chroot for apt.
+    pub fn local_mirror(&self) -> Option<&str> {
+        self.mirror.strip_prefix("file://")
+    }
+
+    pub fn default_locale(&self) -> &str {
+        self.locales.first().map(|l| l.as_str()).unwrap_or(DEFAULT_LOCALE)
+    }
+
+    pub fn backports_suite(&self) -> String {
+        format!("{}-backports", self.release)
+    }
+
+    fn validate(&self, builder: &dyn ImageBuilder) {
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+            halt!("Invalid image name {}. Image names may only contain letters, numbers, '-', '_' and '.'", self.name);
+        }
+        if !builder.releases().contains(&self.release.as_str()) {
+            halt!("Unsupported {} release {}. Supported releases are: {}", builder.distro(), self.release, builder.releases().join(", "));
+        }
+        let qemu_arch = ARCHITECTURES.iter()
+            .find(|(arch, _)| *arch == self.arch)
+            .map(|(_, qemu_arch)| *qemu_arch)
+            .unwrap_or_else(|| halt!(
+                "Unsupported architecture {}. Supported architectures are: {}",
+                self.arch,
+                ARCHITECTURES.iter().map(|(arch, _)| *arch).collect::<Vec<_>>().join(", ")
+            ));
+        if self.components.iter().any(|c| c.is_empty() || !c.chars().all(|ch| ch.is_ascii_lowercase() || ch == '-')) {
+            halt!("Invalid archive components {}", self.components.join(","));
+        }
+        if bootstrap::bootstrapper(&self.bootstrapper).is_none() {
+            halt!("Unsupported bootstrapper {}. Supported bootstrappers are: {}", self.bootstrapper, bootstrap::supported_bootstrappers());
+        }
+        self.validate_localisation();
+        if let Some(snapshot) = &self.snapshot {
+            if !valid_snapshot(snapshot) {
+                halt!("Invalid archive snapshot {}. Snapshots are timestamps like 20240101T000000Z", snapshot);
+            }
+        }
+        for mirror in [Some(&self.mirror), self.security_mirror.as_ref()].into_iter().flatten() {
+            if !(mirror.starts_with("http://") || mirror.starts_with("https://") || mirror.starts_with("file:///")) {
+                halt!("Invalid mirror {}. Mirrors must be http://, https:// or file:/// URLs", mirror);
+            }
+        }
+        if let Some(local_mirror) = self.local_mirror() {
+            if !Path::new(local_mirror).join("dists").is_dir() {
+                halt!("Local mirror {} is not an apt repository: it has no dists directory", local_mirror);
+            }
+        }
+
+        // Foreign architectures are bootstrapped and chrooted into through binfmt emulation
+        if qemu_arch != env::consts::ARCH {
+            which(&format!("qemu-{}-static", qemu_arch));
+            if !Path::new(&format!("/proc/sys/fs/binfmt_misc/qemu-{}", qemu_arch)).exists() {
+                halt!("binfmt support for {} is not registered. Please install qemu-user-static and binfmt-support and try again.", qemu_arch);
+            }
+        }
+
+        builder.check_options(self);
+    }
+}
+
+
+impl Options {
+    /// These values end up on chroot command lines, so they are held to the same patterns
+    /// as the polkit rules.
+    fn validate_localisation(&self) {
+        let valid_locale = |locale: &str| {
+            let (name, modifier) = locale.split_once('@').unwrap_or((locale, "a"));
+            let (language, charset) = name.split_once('.').unwrap_or((name, "a"));
+            let (language, territory) = language.split_once('_').unwrap_or((language, "AA"));
+            (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_lowercase())
+                && (2..=3).contains(&territory.len()) && territory.chars().all(|c| c.is_ascii_uppercase())
+                && !charset.is_empty() && charset.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !modifier.is_empty() && modifier.chars().all(|c| c.is_ascii_lowercase())
+        };
+        if self.locales.is_empty() || !self.locales.iter().all(|l| valid_locale(l)) {
+            halt!("Invalid locales {}. Locales look like en_US.UTF-8", self.locales.join(","));
+        }
+        if self.timezone.is_empty() || self.timezone.starts_with('/') || self.timezone.contains("..")
+            || !self.timezone.chars().all(|c| c.is_ascii_alphanumeric() || "/_-+".contains(c)) {
+            halt!("Invalid timezone {}. Timezones look like Europe/Berlin", self.timezone);
+        }
+        if !(2..=8).contains(&self.keyboard.len()) || !self.keyboard.chars().all(|c| c.is_ascii_lowercase()) {
+            halt!("Invalid keyboard layout {}. Layouts are XKB names like us or de", self.keyboard);
+        }
+        if self.console_font.is_empty() || !self.console_font.chars().all(|c| c.is_ascii_alphanumeric()) {
+            halt!("Invalid console font {}. Fonts are console-setup font faces like Fixed or Terminus", self.console_font);
+        }
+        let valid_label = |label: &str| !label.is_empty() && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-') && !label.ends_with('-');
+        if !self.hostname.split('.').all(valid_label) {
+            halt!("Invalid hostname {}", self.hostname);
+        }
+    }
+}
+
+
+/// The steps needed to build a bootable Root-on-ZFS image for one distribution.
+/// `create` runs them in order, with the image's chroot mounts prepared after `bootstrap`.
+pub trait ImageBuilder: Sync {
+    /// Name used on the command line and in image names
+    fn distro(&self) -> &'static str;
+    fn releases(&self) -> &'static [&'static str];
+    fn default_release(&self) -> &'static str;
+    fn default_mirror(&self, arch: &str) -> &'static str;
+    fn default_components(&self) -> &'static [&'static str];
+    /// Setting holding the site's preferred mirror for the distribution
+    fn mirror_key(&self) -> settings::Key;
+    /// Main and security archive URLs pinned to a snapshot timestamp
+    fn snapshot_mirrors(&self, snapshot: &str, arch: &str) -> (String, String);
+
+    /// Reject option combinations the distribution cannot build
+    fn check_options(&self, _options: &Options) {}
+
+    fn bootstrap(&self, image_path: &str, options: &Options);
+    fn configure_package_manager(&self, image_path: &str, options: &Options);
+    fn configure_locale(&self, image_path: &str, options: &Options);
+    fn install_kernel(&self, image_path: &str, options: &Options);
+    fn enable_services(&self, image_path: &str, options: &Options);
+
+    /// Install additional packages, e.g. those listed in a recipe
+    fn install_packages(&self, image_path: &str, options: &Options, packages: &[&str]);
+}
+
+
+/// What was built into an image, and how to build it again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Image {
+    pub options: Options,
+    /// Absolute path of the recipe the image was built from
+    pub recipe: Option<String>,
+    /// sha256 of the recipe file as it was when the image was built
+    #[serde(default)]
+    pub recipe_hash: Option<String>,
+    pub created: String,
+    /// Set once every build stage has completed
+    pub built: Option<String>,
+}
+
+fn images_db() -> sled::Tree {
+    database().open_tree("images").unwrap_or_else(|e| halt!("Could not open images database tree: {}", e))
+}
+
+fn save_image(image: &Image) {
+    let config = PrettyConfig::new()
+        .struct_names(true)
+        .compact_arrays(false);
+    let data = to_string_pretty(image, config).unwrap_or_else(|e| halt!("Could not serialize data: {}", e));
+
+    let db = images_db();
+    db.insert(image.options.name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+pub fn get_image(image_name: &str) -> Option<Image> {
+    let bytes = images_db().get(image_name.as_bytes()).unwrap_or_else(|e| halt!("Could not retreive data for image {} : {}", image_name, e))?;
+    let string = String::from_utf8_lossy(&bytes);
+    ron::from_str(&string).ok()
+}
+
+
+/// Build a complete image from the command line options.
+pub fn build(builder: &dyn ImageBuilder, options: &Options) {
+    create(builder, options, None);
+    finish(&options.name);
+}
+
+
+/// Build the base image and return its path. `finish` must be called once any further
+/// stages, such as a recipe's, have been applied.
+pub fn create(builder: &dyn ImageBuilder, options: &Options, recipe: Option<&str>) -> String {
+    options.validate(builder);
+
+    let image_path = check_and_create_image_dataset(&options.name);
+    let recipe_hash = recipe.map(|r| manifest::sha256_hex(
+        &fs::read(r).unwrap_or_else(|e| halt!("Could not read recipe file {}: {}", r, e))
+    ));
+    save_image(&Image {
+        options: options.clone(),
+        recipe: recipe.map(|r| r.to_owned()),
+        recipe_hash,
+        created: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        built: None,
+    });
+
+    builder.bootstrap(&image_path, options);
+    snapshot_stage(&options.name, "bootstrap");
+
+    // Copy files
+    copy_config(&image_path, builder.distro());
+    snapshot_stage(&options.name, "config");
+
+    mount_chroot(&image_path);
+    mount_package_sources(&image_path, options);
+
+    builder.configure_package_manager(&image_path, options);
+    snapshot_stage(&options.name, "package-manager");
+    builder.configure_locale(&image_path, options);
+    snapshot_stage(&options.name, "locale");
+    builder.install_kernel(&image_path, options);
+    snapshot_stage(&options.name, "kernel");
+    builder.enable_services(&image_path, options);
+    snapshot_stage(&options.name, "services");
+
+    image_path
+}
+
+
+/// Release the image's chroot mounts, record its manifest and take the final `@built` snapshot.
+pub fn finish(image_name: &str) {
+    unmount_chroot(&image_path(image_name));
+    let mut image = get_image(image_name).unwrap_or_else(|| halt!("No data found for image {}", image_name));
+    manifest::generate(&image);
+    snapshot_stage(image_name, "built");
+    image.built = Some(Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+    save_image(&image);
+    log!("Image {} is complete", image_name);
+}
+
+
+pub fn mount_chroot(image_path: &str) {
+    perform("Mount proc",   None, pkexec(&[&which("mount"), "-t", "proc", "proc",  &format!("{}/proc",    image_path)]), false);
+    perform("Mount sys",    None, pkexec(&[&which("mount"), "-t", "sysfs", "sys",  &format!("{}/sys",     image_path)]), false);
+    perform("Mount dev",    None, pkexec(&[&which("mount"), "-B", "/dev",          &format!("{}/dev",     image_path)]), false);
+    perform("Mount devpts", None, pkexec(&[&which("mount"), "-t", "devpts", "pts", &format!("{}/dev/pts", image_path)]), false);
+}
+
+
+/// Directories polkit allows to be bind mounted into images, maintained by an administrator.
+pub const PACKAGE_SOURCES: &str = "/etc/reflectron/package-sources";
+
+fn check_package_source(description: &str, path: &str) {
+    let allowed = fs::read_to_string(PACKAGE_SOURCES).unwrap_or_default();
+    if !allowed.lines().any(|line| line.trim() == path) {
+        halt!("{} {} is not listed in {}. Ask an administrator to add it so it can be mounted into images.", description, path, PACKAGE_SOURCES);
+    }
+}
+
+/// Share the host's package cache and any local mirror with apt inside the chroot.
+pub fn mount_package_sources(image_path: &str, options: &Options) {
+    if let Some(cache) = package_cache() {
+        check_package_source("Package cache", &cache);
+        let target = format!("{}/var/cache/apt/archives", image_path);
+        perform("Mount package cache", None, pkexec(&[&which("mount"), "-B", &cache, &target]), false);
+    }
+    if let Some(local_mirror) = options.local_mirror() {
+        check_package_source("Local mirror", local_mirror);
+        let target = format!("{}{}", image_path, local_mirror);
+        perform("Create local mirror mountpoint", None, pkexec(&[&which("mkdir"), "-p", &target]), false);
+        perform("Mount local mirror", None, pkexec(&[&which("mount"), "-B", local_mirror, &target]), false);
+    }
+}
+
+
+/// Directory of downloaded .debs shared between builds, if the package-cache setting is set.
+pub fn package_cache() -> Option<String> {
+    let cache = settings::get(settings::Key::PackageCache)?;
+    if !Path::new(&cache).is_absolute() || !Path::new(&cache).is_dir() {
+        halt!("Package cache {} must be an existing directory given as an absolute path. Use 'ref set package-cache <dir>' to change it.", cache);
+    }
+    Some(cache)
+}
+
+
+/// Unmount anything mounted below the image path, deepest first.
+pub fn unmount_chroot(image_path: &str) {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_else(|e| halt!("Could not read /proc/mounts: {}", e));
+    let prefix = format!("{}/", image_path);
+    let mut mountpoints: Vec<&str> = mounts.lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter(|mountpoint| mountpoint.starts_with(&prefix))
+        .collect();
+    mountpoints.sort_by_key(|mountpoint| std::cmp::Reverse(mountpoint.len()));
+
+    for mountpoint in mountpoints {
+        perform(&format!("Unmount {}", mountpoint), None, pkexec(&[&which("umount"), mountpoint]), false);
+    }
+}
+
+
+pub fn image_path(image_name: &str) -> String {
+    format!("{}/{}", IMAGES_DIR, image_name)
+}
+
+pub fn image_dataset(image_name: &str) -> String {
+    format!("{}/reflectron/images/{}", settings::disk_pool(), image_name)
+}
+
+
+/// Create the image's ZFS dataset, mounted at its image path, and return the path. Setuid
+/// bits and device nodes in the image do not work on the host; machines are installed with
+/// a plain send, which leaves both properties behind.
+pub fn check_and_create_image_dataset(image_name: &str) -> String {
+    let image_path = image_path(image_name);
+    let dataset = image_dataset(image_name);
 
     // Get the binary name used to call the program
     let binary_name = env::args().next().unwrap_or_else(|| String::from("reflectron"));
-    let binary_name = binary_name.split('/').last().unwrap_or("reflectron");
+    let binary_name = binary_name.split('/').next_back().unwrap_or("reflectron");
 
-    // Check if the directory already exists
-    if Path::new(&image_path).exists() {
+    // Check if the image already exists
+    if Path::new(&image_path).exists() || success_stauts(zfs(&["list", &dataset])) {
         log!(
-            "Directory {} already exists. Refusing to overwrite the existing image directory out of caution.\n\
+            "Image {} already exists at {}. Refusing to overwrite the existing image out of caution.\n\
             Use '{} image delete {}' to delete the image if you need to recreate it.",
-            image_path, binary_name, image_name
+            image_name, image_path, binary_name, image_name
         );
         exit(1);
     }
 
-    // Create the directory
-    match fs::create_dir_all(&image_path) {
-        Ok(_) => {
-            log!("Created directory: {}", image_path);
-            image_path
-        },
-        Err(e) => {
-            halt!("Failed to create directory {}: {}", image_path, e)
-        }
+    perform(
+        &format!("Create image dataset {}", dataset),
+        None,
+        zfs(&["create", "-p", "-o", "setuid=off", "-o", "devices=off", "-o", &format!("mountpoint={}", image_path), &dataset]),
+        false
+    );
+    image_path
+}
+
+
+/// Snapshot the image after a build stage completes, so later stages or machine roots can
+/// start from a known-good state. Builds do not resume from these snapshots: an interrupted
+/// build has to be deleted and run again.
+pub fn snapshot_stage(image_name: &str, stage: &str) {
+    let snapshot = format!("{}@{}", image_dataset(image_name), stage);
+    perform(
+        &format!("Snapshot {} stage of image {}", stage, image_name),
+        Some(zfs(&["list", &snapshot])),
+        zfs(&["snapshot", &snapshot]),
+        false
+    );
+}
+
+/// All snapshots of the image, oldest first.
+pub fn snapshots(image_name: &str) -> Vec<String> {
+    let mut command = zfs_query(&["list", "-H", "-t", "snapshot", "-o", "name", "-s", "createtxg", &image_dataset(image_name)]);
+    command.stderr(std::process::Stdio::null());
+    match command.output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        _ => Vec::new(),
     }
 }
 
+pub fn latest_snapshot(image_name: &str) -> Option<String> {
+    snapshots(image_name).pop()
+}
+
+
+/// Clone an image snapshot into a new dataset mounted at `mountpoint`.
+pub fn clone_snapshot(snapshot: &str, dataset: &str, mountpoint: &str) {
+    perform(
+        &format!("Clone {} to {}", snapshot, dataset),
+        Some(zfs(&["list", dataset])),
+        zfs(&["clone", "-p", "-o", &format!("mountpoint={}", mountpoint), snapshot, dataset]),
+        false
+    );
+}
+
 
 pub fn image_which(image: &str, program: &str) -> String {
     let paths = vec![
@@ -58,17 +554,217 @@ pub fn image_which(image: &str, program: &str) -> String {
 }
 
 
-pub fn copy_config(image_path: &str) {
-    let current_dir = env::current_dir().unwrap_or_else(|e| halt!("Could not find current directory: {}", e));
-    let dir_path = current_dir.to_str().unwrap_or_else(|| halt!("Could not generate source path for config file copy"));
-    let source_path = format!("{}/files/debian12/etc", dir_path);
+/// Install the distribution's config tree from the data directory, or the copy embedded in the binary.
+pub fn copy_config(image_path: &str, distro: &str) {
+    for (dest, contents) in data::config_files(distro) {
+        install_file(image_path, &dest, "644", &contents);
+    }
+}
+
 
-    let cp_command = pkexec(&[ &which("cp"), "-R", &source_path, &format!("{}/", image_path)]);
+/// Write generated file contents to a path inside the image, creating parent directories as needed.
+pub fn install_file(image_path: &str, dest: &str, mode: &str, contents: impl AsRef<[u8]>) {
+    let mut file = tempfile::NamedTempFile::new().unwrap_or_else(|e| halt!("Could not create temporary file for {}: {}", dest, e));
+    file.write_all(contents.as_ref()).unwrap_or_else(|e| halt!("Could not write temporary file for {}: {}", dest, e));
+    let source = file.path().to_str().unwrap_or_else(|| halt!("Could not generate temporary path for {}", dest)).to_owned();
 
     perform(
-        "Copying config",
+        &format!("Install {}", dest),
         None,
-        cp_command,
-        true
+        pkexec(&[&which("install"), "-D", "-m", mode, &source, &format!("{}{}", image_path, dest)]),
+        false
     );
-}
\ No newline at end of file
+}
+
+
+fn dataset_exists(image_name: &str) -> bool {
+    let mut command = zfs_query(&["list", &image_dataset(image_name)]);
+    command.stderr(std::process::Stdio::null());
+    success_stauts(command)
+}
+
+fn image_size(image_name: &str) -> String {
+    if dataset_exists(image_name) {
+        get(zfs_query(&["get", "-H", "-o", "value", "used", &image_dataset(image_name)])).trim().to_owned()
+    } else {
+        "unknown".to_owned()
+    }
+}
+
+/// Machines built from the image.
+fn image_users(image_name: &str) -> Vec<Machine> {
+    machine::list().into_iter()
+        .filter(|m| m.image.as_deref() == Some(image_name))
+        .collect()
+}
+
+/// Names of all images, including directories left by builds from before images were recorded.
+fn image_names() -> Vec<String> {
+    let mut names: Vec<String> = images_db().iter()
+        .map(|item| item.unwrap_or_else(|e| halt!("Error iterating images tree: {}", e)).0)
+        .map(|key| String::from_utf8_lossy(&key).into_owned())
+        .collect();
+
+    if let Ok(entries) = fs::read_dir(IMAGES_DIR) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names.sort();
+    names
+}
+
+
+pub fn list() {
+    let names = image_names();
+    if names.is_empty() {
+        println!("No images found");
+        return;
+    }
+
+    println!("{:<30} {:<8} {:<10} {:<6} {:<20} {:>8}  RECIPE", "NAME", "DISTRO", "RELEASE", "ARCH", "BUILT", "SIZE");
+    for name in names {
+        match get_image(&name) {
+            Some(image) => println!(
+                "{:<30} {:<8} {:<10} {:<6} {:<20} {:>8}  {}",
+                name,
+                image.options.distro,
+                image.options.release,
+                image.options.arch,
+                image.built.as_deref().unwrap_or("incomplete"),
+                image_size(&name),
+                image.recipe.as_deref().unwrap_or("-"),
+            ),
+            None => println!("{:<30} {:<8} {:<10} {:<6} {:<20} {:>8}  -", name, "?", "?", "?", "unrecorded", image_size(&name)),
+        }
+    }
+}
+
+
+pub fn show(image_name: &str) {
+    let image = get_image(image_name).unwrap_or_else(|| halt!("No image named {}", image_name));
+    let options = &image.options;
+
+    println!("Image: {}", image_name);
+    println!("-------------------");
+    println!("Distribution: {} {} ({})", options.distro, options.release, options.arch);
+    println!("Mirror: {}", options.mirror);
+    println!("Components: {}", options.components.join(", "));
+    println!("Backports: {}", options.backports);
+    println!("Bootstrapper: {}", options.bootstrapper);
+    println!("Locales: {}", options.locales.join(", "));
+    println!("Timezone: {}", options.timezone);
+    println!("Keyboard: {} (console font {})", options.keyboard, options.console_font);
+    println!("Hostname: {}", options.hostname);
+    println!("Recipe: {}", image.recipe.as_deref().unwrap_or("-"));
+    println!("Created: {}", image.created);
+    println!("Built: {}", image.built.as_deref().unwrap_or("incomplete"));
+    println!("Path: {}", image_path(image_name));
+    if dataset_exists(image_name) {
+        println!("Dataset: {}", image_dataset(image_name));
+        println!("Size: {}", image_size(image_name));
+        println!("Snapshots:");
+        for snapshot in snapshots(image_name) {
+            println!("  {}", snapshot);
+        }
+    }
+    if let Some(manifest) = manifest::get_manifest(image_name) {
+        println!("Packages: {}", manifest.packages.len());
+        println!("Kernels: {}", manifest.kernels.join(", "));
+        println!("ZFS: {} (module {})", manifest.zfs_version.as_deref().unwrap_or("-"), manifest.zfs_module.as_deref().unwrap_or("-"));
+        println!("Enabled units: {}", manifest.enabled_units.len());
+        println!("Accounts: {}", manifest.accounts.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(", "));
+        println!("SSH: {}", if manifest.sshd_config.is_some() { "enabled" } else { "not configured" });
+    }
+    let machines = image_users(image_name);
+    if !machines.is_empty() {
+        println!("Used by: {}", machines.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(", "));
+    }
+}
+
+
+pub fn delete(image_name: &str) {
+    let machines = image_users(image_name);
+    if !machines.is_empty() {
+        halt!(
+            "Image {} is still used by machine(s) {}. Refusing to delete it.",
+            image_name,
+            machines.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(", ")
+        );
+    }
+    remove(image_name);
+}
+
+
+/// Remove an image's files and record, whether or not machines still refer to it.
+fn remove(image_name: &str) {
+    let image_path = image_path(image_name);
+    if !Path::new(&image_path).exists() && !dataset_exists(image_name) && get_image(image_name).is_none() {
+        halt!("No image named {}", image_name);
+    }
+
+    unmount_chroot(&image_path);
+
+    if dataset_exists(image_name) {
+        let dataset = image_dataset(image_name);
+        perform(&format!("Destroy image dataset {}", dataset), None, zfs(&["destroy", "-r", &dataset]), false);
+    } else if Path::new(&image_path).exists() {
+        perform(&format!("Remove image directory {}", image_path), None, pkexec(&[&which("rm"), "-rf", "--one-file-system", &image_path]), false);
+    }
+
+    let db = images_db();
+    db.remove(image_name.as_bytes()).unwrap_or_else(|e| halt!("Could not remove data for image {}: {}", image_name, e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+    manifest::remove_manifest(image_name);
+    exec::remove_steps(image_name);
+    log!("Deleted image {}", image_name);
+}
+
+
+/// Delete the image and build it again from its recipe or recorded options.
+pub fn rebuild(image_name: &str) {
+    let image = get_image(image_name).unwrap_or_else(|| halt!("No build record for image {}, it cannot be rebuilt", image_name));
+    let builder = builder(&image.options.distro).unwrap_or_else(|| halt!("Image {} uses unsupported distribution {}", image_name, image.options.distro));
+    if let Some(recipe) = &image.recipe {
+        if !Path::new(recipe).exists() {
+            halt!("Recipe {} for image {} no longer exists", recipe, image_name);
+        }
+    }
+
+    remove(image_name);
+
+    match &image.recipe {
+        Some(recipe) => recipe::create(recipe),
+        None => build(builder, &image.options),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_timestamps() {
+        assert!(valid_snapshot("20240101T000000Z"));
+        assert!(valid_snapshot("20991231T235959Z"));
+    }
+
+    #[test]
+    fn invalid_snapshot_timestamps() {
+        for snapshot in ["", "20240101", "20240101T000000", "20240101T0000000", "2024010AT000000Z", "20240101X000000Z", "20240101T00:000Z", "20240101T000000Z0"] {
+            assert!(!valid_snapshot(snapshot), "{} accepted", snapshot);
+        }
+    }
+
+    #[test]
+    fn multibyte_snapshot_timestamps_are_rejected() {
+        assert!(!valid_snapshot("2024010éT00000Z"));
+        assert!(!valid_snapshot("éééééééé"));
+        assert!(!valid_snapshot("20240101T0000é0Z"));
+    }
+}
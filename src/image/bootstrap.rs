@@ -0,0 +1,114 @@
+use std::path::Path;
+use std::process::Command;
+use crate::*;
+use crate::image::*;
+
+pub const DEFAULT_BOOTSTRAPPER: &str = "debootstrap";
+
+/// Tools that can lay down a Debian-family base system.
+pub const BOOTSTRAPPERS: &[&dyn Bootstrapper] = &[
+    &Debootstrap,
+    &Mmdebstrap,
+];
+
+pub fn bootstrapper(name: &str) -> Option<&'static dyn Bootstrapper> {
+    BOOTSTRAPPERS.iter().copied().find(|b| b.name() == name)
+}
+
+pub fn supported_bootstrappers() -> String {
+    BOOTSTRAPPERS.iter().map(|b| b.name()).collect::<Vec<_>>().join(", ")
+}
+
+
+/// Creates the base system of an image, verifying the archive against `keyring` when the
+/// host's default keyring does not cover the distribution.
+pub trait Bootstrapper: Sync {
+    /// Name used on the command line, in recipes and in image options
+    fn name(&self) -> &'static str;
+    fn bootstrap(&self, image_path: &str, options: &Options, keyring: Option<&str>);
+}
+
+
+/// debootstrap, run as root through polkit.
+pub struct Debootstrap;
+
+impl Bootstrapper for Debootstrap {
+    fn name(&self) -> &'static str {
+        "debootstrap"
+    }
+
+    fn bootstrap(&self, image_path: &str, options: &Options, keyring: Option<&str>) {
+        let debootstrap_path = which("debootstrap");
+
+        let arch = format!("--arch={}", options.arch);
+        let components = format!("--components={}", options.components.join(","));
+        let mut args = vec![&debootstrap_path[..], &arch, &components];
+        let keyring = keyring.map(|k| format!("--keyring={}", k));
+        if let Some(keyring) = &keyring {
+            args.push(keyring);
+        }
+        let cache_dir = package_cache().map(|c| format!("--cache-dir={}", c));
+        if let Some(cache_dir) = &cache_dir {
+            args.push(cache_dir);
+        }
+        args.extend_from_slice(&[&options.release, image_path, &options.mirror]);
+
+        perform(
+            &format!("Run debootstrap in {}", image_path),
+            None,
+            pkexec(&args),
+            true
+        );
+    }
+}
+
+
+/// mmdebstrap in unshare mode. The base system is built as the invoking user inside a user
+/// namespace and written to a tarball, so the only privileged step is unpacking it into the
+/// image. The user needs subordinate ids in /etc/subuid and /etc/subgid.
+pub struct Mmdebstrap;
+
+impl Bootstrapper for Mmdebstrap {
+    fn name(&self) -> &'static str {
+        "mmdebstrap"
+    }
+
+    fn bootstrap(&self, image_path: &str, options: &Options, keyring: Option<&str>) {
+        let mmdebstrap_path = which("mmdebstrap");
+        let work_dir = tempfile::tempdir().unwrap_or_else(|e| halt!("Could not create temporary directory for mmdebstrap: {}", e));
+        let tarball = work_dir.path().join("rootfs.tar").to_string_lossy().into_owned();
+
+        let arch = format!("--architectures={}", options.arch);
+        let components = format!("--components={}", options.components.join(","));
+        let mut args = vec![&mmdebstrap_path[..], "--mode=unshare", &arch, &components];
+        let keyring = keyring.map(|k| format!("--keyring={}", k));
+        if let Some(keyring) = &keyring {
+            args.push(keyring);
+        }
+        // Hooks run outside the namespace, so the cache is shared without privileges
+        let cache_hooks = package_cache().map(|cache| [
+            "--skip=essential/unlink".to_owned(),
+            r#"--setup-hook=mkdir -p "$1"/var/cache/apt/archives"#.to_owned(),
+            format!("--setup-hook=sync-in {} /var/cache/apt/archives", cache),
+            format!("--customize-hook=sync-out /var/cache/apt/archives {}", cache),
+        ]);
+        if let Some(cache_hooks) = &cache_hooks {
+            args.extend(cache_hooks.iter().map(|h| h.as_str()));
+        }
+        args.extend_from_slice(&[&options.release, &tarball, &options.mirror]);
+
+        let mut command = Command::new(args[0]);
+        command.args(&args[1..]);
+        perform(&format!("Run mmdebstrap for {}", image_path), None, command, true);
+
+        if !Path::new(&tarball).is_file() {
+            halt!("mmdebstrap did not produce {}", tarball);
+        }
+        perform(
+            &format!("Unpack base system into {}", image_path),
+            None,
+            pkexec(&[&which("tar"), "-xpf", &tarball, "--numeric-owner", "-C", image_path]),
+            true
+        );
+    }
+}
@@ -1,97 +1,251 @@
-use std::env;
+use std::path::Path;
 use std::process::Command;
 use crate::*;
 use crate::image::*;
 
+pub const SECURITY_MIRROR: &str = "http://deb.debian.org/debian-security";
 
-pub fn create(backports: bool) {
-    let current_dir = env::current_dir().unwrap_or_else(|e| halt!("Could not find current directory: {}", e));
-    let check_dir = current_dir.join("files/debian12/etc/apt");
-    if !check_dir.is_dir() {
-        halt!("reflectron setup needs to be run from the root of the reflectron project git repository.");
+pub struct Debian;
+
+impl Debian {
+    /// sid is a rolling release with no security, updates or backports suites.
+    fn is_rolling(options: &Options) -> bool {
+        options.release == "sid"
+    }
+}
+
+impl ImageBuilder for Debian {
+    fn distro(&self) -> &'static str {
+        "debian"
+    }
+
+    fn releases(&self) -> &'static [&'static str] {
+        &["bookworm", "trixie", "sid"]
+    }
+
+    fn default_release(&self) -> &'static str {
+        "bookworm"
+    }
+
+    fn default_mirror(&self, _arch: &str) -> &'static str {
+        "http://deb.debian.org/debian"
+    }
+
+    fn default_components(&self) -> &'static [&'static str] {
+        &["main", "contrib"]
+    }
+
+    fn mirror_key(&self) -> settings::Key {
+        settings::Key::DebianMirror
+    }
+
+    fn snapshot_mirrors(&self, snapshot: &str, _arch: &str) -> (String, String) {
+        (
+            format!("http://snapshot.debian.org/archive/debian/{}", snapshot),
+            format!("http://snapshot.debian.org/archive/debian-security/{}", snapshot),
+        )
+    }
+
+    fn check_options(&self, options: &Options) {
+        if options.backports && Debian::is_rolling(options) {
+            halt!("Debian {} has no backports suite", options.release);
+        }
+    }
+
+    fn bootstrap(&self, image_path: &str, options: &Options) {
+        debian::bootstrap(image_path, options, None);
+    }
+
+    fn configure_package_manager(&self, image_path: &str, options: &Options) {
+        let mut suites = vec![(options.mirror.clone(), options.release.clone())];
+        if !Debian::is_rolling(options) {
+            if let Some(security_mirror) = security_mirror(options, SECURITY_MIRROR) {
+                suites.push((security_mirror, format!("{}-security", options.release)));
+            }
+            suites.push((options.mirror.clone(), format!("{}-updates", options.release)));
+            if options.backports {
+                suites.push((options.mirror.clone(), options.backports_suite()));
+            }
+        }
+        write_sources(image_path, options, &suites);
+    }
+
+    fn configure_locale(&self, image_path: &str, options: &Options) {
+        configure_localisation(image_path, options);
+    }
+
+    fn install_kernel(&self, image_path: &str, options: &Options) {
+        let headers = format!("linux-headers-{}", options.arch);
+        let image = format!("linux-image-{}", options.arch);
+        perform(
+            "Install packages",
+            None,
+            apt_install(
+                image_path,
+                options,
+                &[
+                    "keyboard-configuration",
+                    "console-setup",
+                    &headers,
+                    &image,
+                    "zfs-initramfs",
+                    "dosfstools",
+                ]
+            ),
+            true
+        );
+    }
+
+    fn enable_services(&self, image_path: &str, _options: &Options) {
+        enable_zfs_services(image_path);
+    }
+
+    fn install_packages(&self, image_path: &str, options: &Options, packages: &[&str]) {
+        perform("Install recipe packages", None, apt_install(image_path, options, packages), true);
+    }
+}
+
+
+/// Bootstrap a Debian-family base system with the image's chosen bootstrapper.
+pub fn bootstrap(image_path: &str, options: &Options, keyring: Option<&str>) {
+    bootstrap::bootstrapper(&options.bootstrapper)
+        .unwrap_or_else(|| halt!("Unsupported bootstrapper {}. Supported bootstrappers are: {}", options.bootstrapper, bootstrap::supported_bootstrappers()))
+        .bootstrap(image_path, options, keyring);
+}
+
+
+/// One `deb` and `deb-src` line per (mirror, suite), all with the same components.
+pub fn sources_list(suites: &[(String, String)], components: &[String]) -> String {
+    let components = components.join(" ");
+    suites.iter()
+        .map(|(mirror, suite)| format!("deb {0} {1} {2}\ndeb-src {0} {1} {2}\n", mirror, suite, components))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+
+/// Security archive for the image: the one given in the options, else `default` unless the
+/// main mirror is a local repository, which is assumed to carry everything the build needs.
+pub fn security_mirror(options: &Options, default: &str) -> Option<String> {
+    match (&options.security_mirror, options.local_mirror()) {
+        (Some(mirror), _) => Some(mirror.clone()),
+        (None, Some(_)) => None,
+        (None, None) => Some(default.to_owned()),
+    }
+}
+
+
+/// Install sources.list and refresh the package lists. Snapshot archives serve Release files
+/// that have long expired, so pinned images are told not to check their validity.
+pub fn write_sources(image_path: &str, options: &Options, suites: &[(String, String)]) {
+    install_file(image_path, "/etc/apt/sources.list", "644", sources_list(suites, &options.components));
+    if let Some(snapshot) = &options.snapshot {
+        install_file(
+            image_path,
+            "/etc/apt/apt.conf.d/99reflectron-snapshot",
+            "644",
+            format!("// Packages pinned to archive snapshot {}\nAcquire::Check-Valid-Until \"false\";\n", snapshot),
+        );
     }
+    perform("Update apt", None, chroot(image_path, &[&which("apt"), "update"]), true);
+}
+
+
+/// Where the debconf answers for the image's localisation are installed
+pub const PRESEED_PATH: &str = "/var/lib/reflectron/preseed.cfg";
+
 
-    let image_path = check_and_create_image_dir("debian12");
+/// The locale.gen line for a locale: its name and character set.
+fn locale_gen_line(locale: &str) -> String {
+    let charset = locale.split_once('.')
+        .map(|(_, charset)| charset.split('@').next().unwrap_or(charset))
+        .unwrap_or("ISO-8859-1");
+    format!("{} {}", locale, charset)
+}
+
+
+/// debconf answers for locales, tzdata, keyboard-configuration and console-setup, so that
+/// packages installed later are configured without prompting.
+pub fn preseed(options: &Options) -> String {
+    let (area, zone) = options.timezone.split_once('/').unwrap_or(("Etc", &options.timezone));
+    let locales: Vec<String> = options.locales.iter().map(|l| locale_gen_line(l)).collect();
+    [
+        format!("locales locales/locales_to_be_generated multiselect {}", locales.join(", ")),
+        format!("locales locales/default_environment_locale select {}", options.default_locale()),
+        format!("tzdata tzdata/Areas select {}", area),
+        format!("tzdata tzdata/Zones/{} select {}", area, zone),
+        format!("keyboard-configuration keyboard-configuration/xkb-keymap select {}", options.keyboard),
+        format!("keyboard-configuration keyboard-configuration/layoutcode string {}", options.keyboard),
+        format!("console-setup console-setup/fontface47 select {}", options.console_font),
+        "console-setup console-setup/charmap47 select UTF-8".to_owned(),
+        String::new(),
+    ].join("\n")
+}
 
-    // Check if debootstrap is installed
-    let debootstrap_path = which("debootstrap");
 
-    // Run debootstrap
+/// Preseed debconf, then generate the image's locales and set its timezone and hostname.
+pub fn configure_localisation(image_path: &str, options: &Options) {
+    install_file(image_path, PRESEED_PATH, "644", preseed(options));
     perform(
-        &format!("Run debootstrap in {}", image_path),
+        "Preseed debconf",
         None,
-        pkexec(&[&debootstrap_path, "bookworm", &image_path]),
+        chroot(image_path, &[&image_which(image_path, "debconf-set-selections"), PRESEED_PATH]),
         true
     );
 
-    // Copy files
-    copy_config(&image_path);
-
-    // Prepare chroot
-    perform("Mount proc",   None, pkexec(&[&which("mount"), "-t", "proc", "proc",  &format!("{}/proc",    &image_path)]), false);
-    perform("Mount sys",    None, pkexec(&[&which("mount"), "-t", "sysfs", "sys",  &format!("{}/sys",     &image_path)]), false);
-    perform("Mount dev",    None, pkexec(&[&which("mount"), "-B", "/dev",          &format!("{}/dev",     &image_path)]), false);
-    perform("Mount devpts", None, pkexec(&[&which("mount"), "-t", "devpts", "pts", &format!("{}/dev/pts", &image_path)]), false);
-
-    // prepare apt
-    perform("Update apt", None, chroot(&image_path, &[&which("apt"), "update"]), true);
-
-    // generate locale
-    perform("Install locales .deb package", None, apt_install(&image_path, backports, &["locales"]), true);
-    copy_config(&image_path);
+    perform("Install locales .deb package", None, apt_install(image_path, options, &["locales"]), true);
+    let locale_gen: Vec<String> = options.locales.iter().map(|l| locale_gen_line(l)).collect();
+    install_file(image_path, "/etc/locale.gen", "644", format!("{}\n", locale_gen.join("\n")));
     perform(
         "Generate locales",
         None,
-        chroot(&image_path, &[&image_which(&image_path, "locale-gen")]),
+        chroot(image_path, &[&image_which(image_path, "locale-gen")]),
         true
     );
 
     // Then set the default locale
+    let lang = format!("LANG={}", options.default_locale());
+    let lc_all = format!("LC_ALL={}", options.default_locale());
     perform(
         "Set default locale",
         None,
-        chroot(&image_path, &[&image_which(&image_path, "update-locale"), "LANG=en_US.UTF-8", "LC_ALL=en_US.UTF-8"]),
+        chroot(image_path, &[&image_which(image_path, "update-locale"), &lang, &lc_all]),
         true
     );
 
-    // install additional packages
+    let zoneinfo = format!("/usr/share/zoneinfo/{}", options.timezone);
+    if !Path::new(&format!("{}{}", image_path, zoneinfo)).is_file() {
+        halt!("Unknown timezone {}: {} does not exist in the image", options.timezone, zoneinfo);
+    }
+    install_file(image_path, "/etc/timezone", "644", format!("{}\n", options.timezone));
     perform(
-        "Install packages",
+        "Set timezone",
         None,
-        apt_install(
-            &image_path,
-            backports,
-            &[
-                "keyboard-configuration",
-                "console-setup",
-                "linux-headers-amd64",
-                "linux-image-amd64",
-                "zfs-initramfs",
-                "dosfstools",
-                ]
-        ),
+        chroot(image_path, &[&image_which(image_path, "ln"), "-sf", &zoneinfo, "/etc/localtime"]),
         true
     );
 
-    // enable services
-    perform("Enable zfs", None, chroot(&image_path, &[&image_which(&image_path, "systemctl"), "enable", "zfs.target"]), true);
-    perform("Enable zfs-import-cache", None, chroot(&image_path, &[&image_which(&image_path, "systemctl"), "enable", "zfs-import-cache"]), true);
-    perform("Enable zfs-mount", None, chroot(&image_path, &[&image_which(&image_path, "systemctl"), "enable", "zfs-mount"]), true);
-    perform("Enable zfs-import", None, chroot(&image_path, &[&image_which(&image_path, "systemctl"), "enable", "zfs-import.target"]), true);
+    install_file(image_path, "/etc/hostname", "644", format!("{}\n", options.hostname));
+}
 
 
+pub fn enable_zfs_services(image_path: &str) {
+    perform("Enable zfs", None, chroot(image_path, &[&image_which(image_path, "systemctl"), "enable", "zfs.target"]), true);
+    perform("Enable zfs-import-cache", None, chroot(image_path, &[&image_which(image_path, "systemctl"), "enable", "zfs-import-cache"]), true);
+    perform("Enable zfs-mount", None, chroot(image_path, &[&image_which(image_path, "systemctl"), "enable", "zfs-mount"]), true);
+    perform("Enable zfs-import", None, chroot(image_path, &[&image_which(image_path, "systemctl"), "enable", "zfs-import.target"]), true);
 }
 
 
-pub fn apt_install(new_root: &str, backports: bool, args: &[&str]) -> Command {
+pub fn apt_install(new_root: &str, options: &Options, args: &[&str]) -> Command {
     let env = image_which(new_root, "env");
     let apt_get = image_which(new_root, "apt-get");
-    let mut apt_args = if backports {
-        vec![&env, "DEBIAN_FRONTEND=noninteractive", &apt_get, "install", "-y", "-t", "bookworm-backports"]
+    let backports_suite = options.backports_suite();
+    let mut apt_args = if options.backports {
+        vec![&env, "DEBIAN_FRONTEND=noninteractive", &apt_get, "install", "-y", "-t", &backports_suite]
     } else {
         vec![&env, "DEBIAN_FRONTEND=noninteractive", &apt_get, "install", "-y"]
     };
     apt_args.extend_from_slice(args);
     chroot(new_root, &apt_args)
 }
-
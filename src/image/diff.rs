@@ -0,0 +1,273 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Serialize;
+use crate::*;
+use crate::image::*;
+use crate::image::manifest::{Manifest, sha256_file};
+
+/// Trees compared file by file. Text files under /etc also get a content diff.
+const TREES: &[&str] = &["/etc", "/usr"];
+const TEXT_DIFF_TREE: &str = "/etc/";
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImageDiff {
+    pub a: String,
+    pub b: String,
+    pub packages: PackageChanges,
+    pub units: Changes,
+    pub files: FileChanges,
+    /// Unified diffs of changed text files under /etc, keyed by path
+    pub diffs: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct PackageChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<VersionChange>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VersionChange {
+    pub name: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct FileChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// Files of the same size whose contents could not be read to compare
+    pub unknown: Vec<String>,
+}
+
+/// What a path in the tree is, enough to tell whether it changed.
+#[derive(PartialEq, Debug)]
+enum Entry {
+    File(u64),
+    Symlink(String),
+    Other,
+}
+
+
+/// Resolve `<image>` or `<image>@<snapshot>` to a root path and its manifest. The manifest is
+/// always collected from the tree itself: the stored one describes the image as built, which
+/// no longer matches once 'ref image exec' has changed it.
+fn resolve(spec: &str) -> (String, Manifest) {
+    let root = match spec.split_once('@') {
+        Some((image_name, snapshot)) => {
+            let root = format!("{}/.zfs/snapshot/{}", image_path(image_name), snapshot);
+            if !Path::new(&root).is_dir() {
+                halt!("Snapshot {} of image {} does not exist", snapshot, image_name);
+            }
+            root
+        }
+        None => {
+            let root = image_path(spec);
+            if !Path::new(&root).is_dir() {
+                halt!("No image named {}", spec);
+            }
+            root
+        }
+    };
+    let manifest = manifest::collect(&root);
+    (root, manifest)
+}
+
+
+fn walk(root: &str, tree: &str, entries: &mut BTreeMap<String, Entry>) {
+    let mut pending = vec![format!("{}{}", root, tree)];
+    while let Some(path) = pending.pop() {
+        let dir = match fs::read_dir(&path) {
+            Ok(dir) => dir,
+            Err(_) => continue,
+        };
+        for item in dir.flatten() {
+            let full_path = item.path();
+            let relative = full_path.to_string_lossy()[root.len()..].to_owned();
+            let metadata = match fs::symlink_metadata(&full_path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let entry = if metadata.file_type().is_symlink() {
+                Entry::Symlink(fs::read_link(&full_path).map(|t| t.to_string_lossy().into_owned()).unwrap_or_default())
+            } else if metadata.is_dir() {
+                pending.push(full_path.to_string_lossy().into_owned());
+                continue;
+            } else if metadata.is_file() {
+                Entry::File(metadata.len())
+            } else {
+                Entry::Other
+            };
+            entries.insert(relative, entry);
+        }
+    }
+}
+
+/// Files of equal size are compared by checksum, falling back to the manifests' /etc
+/// checksums for files only root can read. None if neither tells.
+fn same_content(path: &str, root_a: &str, root_b: &str, manifest_a: &Manifest, manifest_b: &Manifest) -> Option<bool> {
+    match (sha256_file(Path::new(&format!("{}{}", root_a, path))), sha256_file(Path::new(&format!("{}{}", root_b, path)))) {
+        (Ok(a), Ok(b)) => Some(a == b),
+        _ => match (manifest_a.etc_checksums.get(path), manifest_b.etc_checksums.get(path)) {
+            (Some(a), Some(b)) => Some(a == b),
+            _ => None,
+        },
+    }
+}
+
+fn is_text(path: &str) -> bool {
+    match fs::read(path) {
+        Ok(bytes) => !bytes.iter().take(8192).any(|b| *b == 0),
+        Err(_) => false,
+    }
+}
+
+fn text_diff(path: &str, root_a: &str, root_b: &str) -> Option<String> {
+    let file_a = format!("{}{}", root_a, path);
+    let file_b = format!("{}{}", root_b, path);
+    if !is_text(&file_a) || !is_text(&file_b) {
+        return None;
+    }
+    let output = Command::new(which("diff"))
+        .args(["-u", "--label", &format!("a{}", path), "--label", &format!("b{}", path), &file_a, &file_b])
+        .output()
+        .unwrap_or_else(|e| halt!("Could not run diff on {}: {}", path, e));
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+
+pub fn compare(a: &str, b: &str) -> ImageDiff {
+    let (root_a, manifest_a) = resolve(a);
+    let (root_b, manifest_b) = resolve(b);
+    let mut diff = ImageDiff { a: a.to_owned(), b: b.to_owned(), ..Default::default() };
+
+    let packages_a: BTreeMap<&str, &str> = manifest_a.packages.iter().map(|p| (p.name.as_str(), p.version.as_str())).collect();
+    let packages_b: BTreeMap<&str, &str> = manifest_b.packages.iter().map(|p| (p.name.as_str(), p.version.as_str())).collect();
+    for (name, version) in &packages_b {
+        match packages_a.get(name) {
+            None => diff.packages.added.push(format!("{} {}", name, version)),
+            Some(from) if from != version => diff.packages.changed.push(VersionChange {
+                name: name.to_string(),
+                from: from.to_string(),
+                to: version.to_string(),
+            }),
+            _ => {}
+        }
+    }
+    for (name, version) in &packages_a {
+        if !packages_b.contains_key(name) {
+            diff.packages.removed.push(format!("{} {}", name, version));
+        }
+    }
+
+    let units_a: BTreeSet<&String> = manifest_a.enabled_units.iter().collect();
+    let units_b: BTreeSet<&String> = manifest_b.enabled_units.iter().collect();
+    diff.units.added = units_b.difference(&units_a).map(|u| u.to_string()).collect();
+    diff.units.removed = units_a.difference(&units_b).map(|u| u.to_string()).collect();
+
+    let mut files_a = BTreeMap::new();
+    let mut files_b = BTreeMap::new();
+    for tree in TREES {
+        walk(&root_a, tree, &mut files_a);
+        walk(&root_b, tree, &mut files_b);
+    }
+    for (path, entry_b) in &files_b {
+        match files_a.get(path) {
+            None => diff.files.added.push(path.clone()),
+            Some(entry_a) => {
+                let changed = match (entry_a, entry_b) {
+                    (Entry::File(size_a), Entry::File(size_b)) if size_a == size_b => {
+                        match same_content(path, &root_a, &root_b, &manifest_a, &manifest_b) {
+                            Some(same) => !same,
+                            None => {
+                                diff.files.unknown.push(path.clone());
+                                false
+                            }
+                        }
+                    }
+                    _ => entry_a != entry_b,
+                };
+                if changed {
+                    diff.files.changed.push(path.clone());
+                    if path.starts_with(TEXT_DIFF_TREE) && matches!(entry_b, Entry::File(_)) {
+                        if let Some(text) = text_diff(path, &root_a, &root_b) {
+                            diff.diffs.insert(path.clone(), text);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    diff.files.removed = files_a.keys().filter(|p| !files_b.contains_key(*p)).cloned().collect();
+
+    diff
+}
+
+
+pub fn print(diff: &ImageDiff) {
+    println!("Comparing {} with {}", diff.a, diff.b);
+    println!(
+        "Packages: {} added, {} removed, {} changed",
+        diff.packages.added.len(), diff.packages.removed.len(), diff.packages.changed.len()
+    );
+    for package in &diff.packages.added {
+        println!("  + {}", package);
+    }
+    for package in &diff.packages.removed {
+        println!("  - {}", package);
+    }
+    for change in &diff.packages.changed {
+        println!("  ~ {} {} -> {}", change.name, change.from, change.to);
+    }
+
+    println!("Enabled units: {} added, {} removed", diff.units.added.len(), diff.units.removed.len());
+    for unit in &diff.units.added {
+        println!("  + {}", unit);
+    }
+    for unit in &diff.units.removed {
+        println!("  - {}", unit);
+    }
+
+    println!(
+        "Files: {} added, {} removed, {} changed, {} unknown",
+        diff.files.added.len(), diff.files.removed.len(), diff.files.changed.len(), diff.files.unknown.len()
+    );
+    for path in &diff.files.added {
+        println!("  A {}", path);
+    }
+    for path in &diff.files.removed {
+        println!("  D {}", path);
+    }
+    for path in &diff.files.changed {
+        println!("  M {}", path);
+    }
+    for path in &diff.files.unknown {
+        println!("  ? {}", path);
+    }
+
+    for text in diff.diffs.values() {
+        println!();
+        print!("{}", text);
+    }
+}
+
+
+pub fn run(a: &str, b: &str, json: bool) {
+    let diff = compare(a, b);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap_or_else(|e| halt!("Could not serialize diff: {}", e)));
+    } else {
+        print(&diff);
+    }
+}
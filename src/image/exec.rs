@@ -0,0 +1,200 @@
+use std::fs;
+use std::path::Path;
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+use crate::*;
+use crate::image::*;
+use crate::image::recipe::Recipe;
+
+/// Scripts are installed here inside the image before they are run.
+pub const SCRIPTS_DIR: &str = "/root/reflectron-hooks";
+const LOG_DIR: &str = "/var/log/reflectron/images";
+
+/// A script run inside an image, in the order it was run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    /// "hook" for recipe hooks, "exec" for commands run with 'ref image exec'
+    pub kind: String,
+    pub checksum: String,
+    pub started: String,
+    /// Unset if the script failed or was interrupted
+    pub finished: Option<String>,
+    pub log: String,
+}
+
+
+fn steps_db() -> sled::Tree {
+    database().open_tree("image_steps").unwrap_or_else(|e| halt!("Could not open image steps database tree: {}", e))
+}
+
+pub fn steps(image_name: &str) -> Vec<Step> {
+    steps_db().get(image_name.as_bytes())
+        .unwrap_or_else(|e| halt!("Could not retreive steps for image {} : {}", image_name, e))
+        .and_then(|bytes| ron::from_str(&String::from_utf8_lossy(&bytes)).ok())
+        .unwrap_or_default()
+}
+
+fn save_steps(image_name: &str, steps: &[Step]) {
+    let data = ron::to_string(steps).unwrap_or_else(|e| halt!("Could not serialize steps: {}", e));
+    let db = steps_db();
+    db.insert(image_name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+pub fn remove_steps(image_name: &str) {
+    let db = steps_db();
+    db.remove(image_name.as_bytes()).unwrap_or_else(|e| halt!("Could not remove steps for image {}: {}", image_name, e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+
+/// Whether a hook with this name and content has already run to completion in the image.
+pub fn applied(image_name: &str, name: &str, checksum: &str) -> bool {
+    steps(image_name).iter().any(|s| s.kind == "hook" && s.name == name && s.checksum == checksum && s.finished.is_some())
+}
+
+
+/// Image metadata exposed to scripts. Values must not contain whitespace, since polkit
+/// checks the command line token by token.
+fn environment(options: &Options, step: &str) -> Vec<String> {
+    vec![
+        "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_owned(),
+        "HOME=/root".to_owned(),
+        "DEBIAN_FRONTEND=noninteractive".to_owned(),
+        format!("REFLECTRON_IMAGE={}", options.name),
+        format!("REFLECTRON_DISTRO={}", options.distro),
+        format!("REFLECTRON_RELEASE={}", options.release),
+        format!("REFLECTRON_ARCH={}", options.arch),
+        format!("REFLECTRON_MIRROR={}", options.mirror),
+        format!("REFLECTRON_SNAPSHOT={}", options.snapshot.as_deref().unwrap_or("")),
+        format!("REFLECTRON_STEP={}", step),
+    ]
+}
+
+
+/// Install `script` into the image as `name` and run it with streamed output, logged to
+/// /var/log/reflectron/images/<image>/. The run is recorded as a step of the image.
+pub fn run_script(image_path: &str, options: &Options, kind: &str, name: &str, script: &str) {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@:".contains(c)) {
+        halt!("Invalid script name {}", name);
+    }
+    let log_dir = format!("{}/{}", LOG_DIR, options.name);
+    fs::create_dir_all(&log_dir).unwrap_or_else(|e| halt!("Could not create log directory {}: {}", log_dir, e));
+
+    let mut steps = steps(&options.name);
+    let started = Local::now();
+    let log = format!("{}/{:03}-{}.log", log_dir, steps.len() + 1, name);
+    steps.push(Step {
+        name: name.to_owned(),
+        kind: kind.to_owned(),
+        checksum: manifest::sha256_hex(script.as_bytes()),
+        started: started.format("%Y-%m-%d %H:%M:%S").to_string(),
+        finished: None,
+        log: log.clone(),
+    });
+    save_steps(&options.name, &steps);
+
+    let dest = format!("{}/{}", SCRIPTS_DIR, name);
+    install_file(image_path, &dest, "755", script);
+    let env = image_which(image_path, "env");
+    let sh = image_which(image_path, "sh");
+    let environment = environment(options, name);
+    let mut args = vec![env.as_str()];
+    args.extend(environment.iter().map(|v| v.as_str()));
+    args.extend_from_slice(&[&sh, &dest]);
+    perform_logged(&format!("Run {} {}", kind, name), chroot(image_path, &args), &log);
+
+    if let Some(step) = steps.last_mut() {
+        step.finished = Some(Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    save_steps(&options.name, &steps);
+}
+
+
+/// Quote a word for the shell.
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// Mount what the chroot needs, unless a build has left it mounted already.
+fn prepare(image_path: &str, options: &Options) {
+    let mounted = fs::read_to_string("/proc/mounts").unwrap_or_default()
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(&format!("{}/proc", image_path)));
+    if !mounted {
+        mount_chroot(image_path);
+        mount_package_sources(image_path, options);
+    }
+}
+
+/// Release the chroot and, for a built image, bring the manifest and the `@built` snapshot
+/// that machines are installed from up to date with what the scripts changed. The previous
+/// `@built` is kept as `@built-<timestamp>`.
+fn conclude(image: &Image) {
+    let image_name = &image.options.name;
+    unmount_chroot(&image_path(image_name));
+    if image.built.is_some() {
+        manifest::generate(image);
+        let built = format!("{}@built", image_dataset(image_name));
+        let mut exists = zfs_query(&["list", "-t", "snapshot", &built]);
+        exists.stderr(std::process::Stdio::null());
+        if success_stauts(exists) {
+            let previous = format!("{}@built-{}", image_dataset(image_name), Local::now().format("%Y%m%dT%H%M%S"));
+            perform(&format!("Keep previous built snapshot as {}", previous), None, zfs(&["rename", &built, &previous]), false);
+        }
+        snapshot_stage(image_name, "built");
+    }
+}
+
+
+/// Run a command inside an image, e.g. `ref image exec web -- apt-get install -y htop`.
+pub fn exec(image_name: &str, command: &[String]) {
+    let image = get_image(image_name).unwrap_or_else(|| halt!("No image named {}", image_name));
+    let image_path = image_path(image_name);
+    if !Path::new(&image_path).is_dir() {
+        halt!("Image {} has no root at {}", image_name, image_path);
+    }
+    if command.is_empty() {
+        halt!("No command given to run in image {}", image_name);
+    }
+
+    let script = format!("#!/bin/sh\nset -e\nexec {}\n", command.iter().map(|w| quote(w)).collect::<Vec<_>>().join(" "));
+    let name = format!("exec-{}", Local::now().format("%Y%m%dT%H%M%S"));
+    prepare(&image_path, &image.options);
+    run_script(&image_path, &image.options, "exec", &name, &script);
+    conclude(&image);
+}
+
+
+/// Run the recipe's hooks in an image, skipping those already applied unchanged. Used to
+/// resume after a failing hook has been fixed.
+pub fn run_hooks(image_name: &str) {
+    let image = get_image(image_name).unwrap_or_else(|| halt!("No image named {}", image_name));
+    let recipe_path = image.recipe.clone().unwrap_or_else(|| halt!("Image {} was not built from a recipe and has no hooks", image_name));
+    let recipe = Recipe::load(&recipe_path);
+    let recipe_dir = Path::new(&recipe_path).parent().unwrap_or(Path::new(".")).to_path_buf();
+    let image_path = image_path(image_name);
+
+    prepare(&image_path, &image.options);
+    recipe::run_hooks(&recipe, &recipe_dir, &image_path, &image.options);
+    conclude(&image);
+}
+
+
+pub fn print_steps(image_name: &str) {
+    let steps = steps(image_name);
+    if steps.is_empty() {
+        println!("No scripts have been run in image {}", image_name);
+    }
+    for step in steps {
+        println!(
+            "{} {} {} {} ({})",
+            step.started,
+            step.kind,
+            step.name,
+            if step.finished.is_some() { "ok" } else { "FAILED" },
+            step.log
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::collections::BTreeMap;
+use chrono::Utc;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use sha2::{Sha256, Digest};
+use crate::*;
+use crate::image::*;
+
+/// Where the manifest is installed inside the image. Outside /etc so it does not checksum itself.
+pub const MANIFEST_PATH: &str = "/var/lib/reflectron/manifest.json";
+
+/// A record of everything that ended up in an image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub image: String,
+    pub generated: String,
+    pub distro: String,
+    pub release: String,
+    pub arch: String,
+    pub recipe_hash: Option<String>,
+    pub kernels: Vec<String>,
+    pub zfs_version: Option<String>,
+    /// zfs-dkms version, or the kernel providing the module on distributions that ship it built in
+    pub zfs_module: Option<String>,
+    pub packages: Vec<Package>,
+    pub enabled_units: Vec<String>,
+    /// sha256 of every regular file under /etc, keyed by absolute path inside the image
+    pub etc_checksums: BTreeMap<String, String>,
+    /// root and regular login accounts
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    /// Keys installed by reflectron, keyed by user
+    #[serde(default)]
+    pub authorized_keys: BTreeMap<String, Vec<String>>,
+    /// Files under /etc/sudoers.d
+    #[serde(default)]
+    pub sudoers: Vec<String>,
+    /// sshd policy installed by reflectron
+    #[serde(default)]
+    pub sshd_config: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub groups: Vec<String>,
+    pub shell: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+}
+
+
+fn manifests_db() -> sled::Tree {
+    database().open_tree("manifests").unwrap_or_else(|e| halt!("Could not open manifests database tree: {}", e))
+}
+
+pub fn get_manifest(image_name: &str) -> Option<Manifest> {
+    let bytes = manifests_db().get(image_name.as_bytes()).unwrap_or_else(|e| halt!("Could not retreive manifest for image {} : {}", image_name, e))?;
+    let string = String::from_utf8_lossy(&bytes);
+    ron::from_str(&string).ok()
+}
+
+fn save_manifest(manifest: &Manifest) {
+    let data = ron::to_string(manifest).unwrap_or_else(|e| halt!("Could not serialize manifest: {}", e));
+    let db = manifests_db();
+    db.insert(manifest.image.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+pub fn remove_manifest(image_name: &str) {
+    let db = manifests_db();
+    db.remove(image_name.as_bytes()).unwrap_or_else(|e| halt!("Could not remove manifest for image {}: {}", image_name, e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 65536];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+
+/// Installed packages from the root's dpkg status database, sorted by name.
+pub fn packages(root: &str) -> Vec<Package> {
+    let status_path = format!("{}/var/lib/dpkg/status", root);
+    let status = fs::read_to_string(&status_path).unwrap_or_else(|e| halt!("Could not read dpkg status {}: {}", status_path, e));
+
+    let mut packages: Vec<Package> = status.split("\n\n")
+        .filter_map(|paragraph| {
+            let mut fields = BTreeMap::new();
+            for line in paragraph.lines() {
+                if let Some((key, value)) = line.split_once(": ") {
+                    fields.insert(key, value.trim());
+                }
+            }
+            if !fields.get("Status")?.ends_with(" installed") {
+                return None;
+            }
+            Some(Package {
+                name: fields.get("Package")?.to_string(),
+                version: fields.get("Version")?.to_string(),
+                arch: fields.get("Architecture").unwrap_or(&"all").to_string(),
+            })
+        })
+        .collect();
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    packages
+}
+
+fn kernels(root: &str) -> Vec<String> {
+    let mut kernels: Vec<String> = fs::read_dir(format!("{}/lib/modules", root))
+        .map(|entries| entries.flatten().map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    kernels.sort();
+    kernels
+}
+
+fn enabled_units(root: &str) -> Vec<String> {
+    let mut units = Vec::new();
+    if let Ok(entries) = fs::read_dir(format!("{}/etc/systemd/system", root)) {
+        for entry in entries.flatten() {
+            let dir_name = entry.file_name().to_string_lossy().into_owned();
+            if !(dir_name.ends_with(".wants") || dir_name.ends_with(".requires")) {
+                continue;
+            }
+            if let Ok(links) = fs::read_dir(entry.path()) {
+                units.extend(links.flatten().map(|l| l.file_name().to_string_lossy().into_owned()));
+            }
+        }
+    }
+    units.sort();
+    units.dedup();
+    units
+}
+
+/// root and accounts with uids in the regular user range, with their supplementary groups.
+fn accounts(root: &str) -> Vec<Account> {
+    let passwd = fs::read_to_string(format!("{}/etc/passwd", root)).unwrap_or_default();
+    let group = fs::read_to_string(format!("{}/etc/group", root)).unwrap_or_default();
+
+    passwd.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let uid: u32 = fields.get(2)?.parse().ok()?;
+            if uid != 0 && !(1000..65534).contains(&uid) {
+                return None;
+            }
+            let name = fields[0].to_owned();
+            let groups = group.lines()
+                .filter_map(|line| {
+                    let fields: Vec<&str> = line.split(':').collect();
+                    fields.get(3)?.split(',').any(|member| member == name).then(|| fields[0].to_owned())
+                })
+                .collect();
+            Some(Account { name, uid, groups, shell: fields.get(6)?.to_string() })
+        })
+        .collect()
+}
+
+fn authorized_keys(root: &str) -> BTreeMap<String, Vec<String>> {
+    fs::read_dir(format!("{}{}", root, access::AUTHORIZED_KEYS_DIR))
+        .map(|entries| entries.flatten()
+            .map(|entry| (
+                entry.file_name().to_string_lossy().into_owned(),
+                fs::read_to_string(entry.path()).unwrap_or_default().lines().map(|l| l.to_owned()).collect(),
+            ))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn sudoers(root: &str) -> Vec<String> {
+    let mut sudoers: Vec<String> = fs::read_dir(format!("{}{}", root, access::SUDOERS_DIR))
+        .map(|entries| entries.flatten().map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    sudoers.sort();
+    sudoers
+}
+
+/// Checksums of every regular file below `dir`. Files only root can read are
+/// checksummed through a single privileged sha256sum call.
+fn checksums(root: &str, dir: &str) -> BTreeMap<String, String> {
+    let mut checksums = BTreeMap::new();
+    let mut unreadable = Vec::new();
+    let mut pending = vec![format!("{}{}", root, dir)];
+
+    while let Some(path) = pending.pop() {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            let full_path = entry.path().to_string_lossy().into_owned();
+            if file_type.is_dir() {
+                pending.push(full_path);
+            } else if file_type.is_file() {
+                match sha256_file(&entry.path()) {
+                    Ok(sum) => { checksums.insert(full_path[root.len()..].to_owned(), sum); }
+                    Err(_) => unreadable.push(full_path),
+                }
+            }
+        }
+    }
+
+    for chunk in unreadable.chunks(200) {
+        let mut args = vec![which("sha256sum"), "-z".to_owned()];
+        args.extend(chunk.iter().cloned());
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        for (sum, path) in parse_sha256sums(&get(pkexec(&args))) {
+            checksums.insert(path[root.len()..].to_owned(), sum.to_owned());
+        }
+    }
+
+    checksums
+}
+
+/// Checksums and paths from the output of `sha256sum -z`, which ends each line with a NUL
+/// and leaves file names unescaped. Without -z, names containing a backslash or newline
+/// are escaped and their lines start with a backslash.
+pub fn parse_sha256sums(output: &str) -> Vec<(&str, &str)> {
+    output.split('\0')
+        .filter_map(|line| line.split_once("  "))
+        .collect()
+}
+
+
+/// Inspect a root filesystem, which may be an image or one of its snapshots.
+pub fn collect(root: &str) -> Manifest {
+    let packages = packages(root);
+    let version_of = |name: &str| packages.iter().find(|p| p.name == name).map(|p| p.version.clone());
+    let kernels = kernels(root);
+    let zfs_module = version_of("zfs-dkms").or_else(|| {
+        kernels.iter()
+            .find(|k| Path::new(&format!("{}/lib/modules/{}/kernel/zfs", root, k)).exists())
+            .map(|k| format!("kernel {}", k))
+    });
+
+    Manifest {
+        generated: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        zfs_version: version_of("zfsutils-linux"),
+        zfs_module,
+        kernels,
+        enabled_units: enabled_units(root),
+        etc_checksums: checksums(root, "/etc"),
+        accounts: accounts(root),
+        authorized_keys: authorized_keys(root),
+        sudoers: sudoers(root),
+        sshd_config: fs::read_to_string(format!("{}{}", root, access::SSHD_CONFIG_PATH)).ok(),
+        packages,
+        ..Default::default()
+    }
+}
+
+
+/// Generate the image's manifest, store it in the database and install it into the image.
+pub fn generate(image: &Image) -> Manifest {
+    let options = &image.options;
+    let manifest = Manifest {
+        image: options.name.clone(),
+        distro: options.distro.clone(),
+        release: options.release.clone(),
+        arch: options.arch.clone(),
+        recipe_hash: image.recipe_hash.clone(),
+        ..collect(&image_path(&options.name))
+    };
+
+    save_manifest(&manifest);
+    let json = serde_json::to_string_pretty(&manifest).unwrap_or_else(|e| halt!("Could not serialize manifest: {}", e));
+    install_file(&image_path(&options.name), MANIFEST_PATH, "644", &json);
+    log!("Recorded manifest for image {}: {} packages, kernel {}", options.name, manifest.packages.len(), manifest.kernels.join(", "));
+    manifest
+}
+
+
+fn purl(manifest: &Manifest, package: &Package) -> String {
+    format!("pkg:deb/{}/{}@{}?arch={}", manifest.distro, package.name, package.version, package.arch)
+}
+
+pub fn spdx(manifest: &Manifest) -> serde_json::Value {
+    let mut packages = vec![json!({
+        "name": manifest.image,
+        "SPDXID": "SPDXRef-Image",
+        "versionInfo": format!("{} {}", manifest.distro, manifest.release),
+        "downloadLocation": "NOASSERTION",
+        "primaryPackagePurpose": "OPERATING-SYSTEM",
+    })];
+    let mut relationships = vec![json!({
+        "spdxElementId": "SPDXRef-DOCUMENT",
+        "relationshipType": "DESCRIBES",
+        "relatedSpdxElement": "SPDXRef-Image",
+    })];
+
+    for (i, package) in manifest.packages.iter().enumerate() {
+        let id = format!("SPDXRef-Package-{}", i);
+        packages.push(json!({
+            "name": package.name,
+            "SPDXID": id,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": purl(manifest, package),
+            }],
+        }));
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-Image",
+            "relationshipType": "CONTAINS",
+            "relatedSpdxElement": id,
+        }));
+    }
+
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": manifest.image,
+        "documentNamespace": format!("https://github.com/OtherJohnGray/reflectron/spdx/{}-{}", manifest.image, manifest.generated),
+        "creationInfo": {
+            "created": manifest.generated,
+            "creators": [format!("Tool: reflectron-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+pub fn cyclonedx(manifest: &Manifest) -> serde_json::Value {
+    let components: Vec<serde_json::Value> = manifest.packages.iter()
+        .map(|package| json!({
+            "type": "library",
+            "bom-ref": purl(manifest, package),
+            "name": package.name,
+            "version": package.version,
+            "purl": purl(manifest, package),
+        }))
+        .collect();
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "timestamp": manifest.generated,
+            "tools": [{ "vendor": "reflectron", "name": "reflectron", "version": env!("CARGO_PKG_VERSION") }],
+            "component": {
+                "type": "operating-system",
+                "name": manifest.image,
+                "version": format!("{} {}", manifest.distro, manifest.release),
+            },
+        },
+        "components": components,
+    })
+}
+
+
+pub fn export(image_name: &str, format: &str, output: Option<&str>) {
+    let manifest = get_manifest(image_name).unwrap_or_else(|| halt!("No manifest recorded for image {}. Rebuild the image to generate one.", image_name));
+    let document = match format {
+        "spdx" => spdx(&manifest),
+        "cyclonedx" => cyclonedx(&manifest),
+        _ => halt!("Unsupported SBOM format {}. Supported formats are: spdx, cyclonedx", format),
+    };
+    let json = serde_json::to_string_pretty(&document).unwrap_or_else(|e| halt!("Could not serialize SBOM: {}", e));
+
+    match output {
+        Some(path) => {
+            fs::write(path, json).unwrap_or_else(|e| halt!("Could not write SBOM to {}: {}", path, e));
+            log!("Wrote {} SBOM for image {} to {}", format, image_name, path);
+        }
+        None => println!("{}", json),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256sums_keep_unusual_names() {
+        let output = "aa  /img/etc/plain\0bb  /img/etc/back\\slash\0cc  /img/etc/new\nline\0dd  /img/etc/two  spaces\0";
+        assert_eq!(parse_sha256sums(output), vec![
+            ("aa", "/img/etc/plain"),
+            ("bb", "/img/etc/back\\slash"),
+            ("cc", "/img/etc/new\nline"),
+            ("dd", "/img/etc/two  spaces"),
+        ]);
+        assert!(parse_sha256sums("").is_empty());
+    }
+}
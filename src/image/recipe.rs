@@ -0,0 +1,322 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use crate::*;
+use crate::image::*;
+use crate::access::{self, User, Ssh};
+use serde::{Serialize, Deserialize};
+
+/// An image build described in YAML, or in RON for files ending in `.ron`.
+/// Relative paths in `files` and `hooks` are resolved against the recipe's directory.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Recipe {
+    pub distro: String,
+    /// Image name, defaults to <distro>-<release>-<arch>
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub release: Option<String>,
+    #[serde(default)]
+    pub arch: Option<String>,
+    #[serde(default)]
+    pub mirror: Option<String>,
+    #[serde(default)]
+    pub security_mirror: Option<String>,
+    #[serde(default)]
+    pub components: Option<Vec<String>>,
+    /// Archive snapshot timestamp to pin packages to, e.g. 20240101T000000Z
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// debootstrap or mmdebstrap
+    #[serde(default)]
+    pub bootstrapper: Option<String>,
+    /// Locales to generate, the first is the default
+    #[serde(default)]
+    pub locales: Option<Vec<String>>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub keyboard: Option<String>,
+    #[serde(default)]
+    pub console_font: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub backports: bool,
+    #[serde(default)]
+    pub packages: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<File>,
+    #[serde(default)]
+    pub services: Services,
+    /// Groups created before the users
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// sshd policy. openssh-server is installed when this is given or any user has keys
+    #[serde(default)]
+    pub ssh: Option<Ssh>,
+    /// Scripts run inside the image, in order, after everything else is installed
+    #[serde(default)]
+    pub hooks: Vec<String>,
+    /// Templates rendered with each machine's facts into its overlay at deploy time
+    #[serde(default)]
+    pub overlay: Vec<File>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct File {
+    pub source: String,
+    /// Absolute path inside the image
+    pub dest: String,
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    /// Render `{{ variable }}` references before installing
+    #[serde(default)]
+    pub template: bool,
+}
+
+fn default_mode() -> String {
+    "644".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Services {
+    #[serde(default)]
+    pub enable: Vec<String>,
+    #[serde(default)]
+    pub disable: Vec<String>,
+}
+
+
+impl Recipe {
+    pub fn load(path: &str) -> Recipe {
+        let contents = fs::read_to_string(path).unwrap_or_else(|e| halt!("Could not read recipe file {}: {}", path, e));
+        let recipe: Recipe = if path.ends_with(".ron") {
+            ron::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse recipe file {}: {}", path, e))
+        } else {
+            serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse recipe file {}: {}", path, e))
+        };
+        for file in recipe.files.iter().chain(&recipe.overlay) {
+            if !file.dest.starts_with('/') || file.dest.split('/').any(|part| part == "..") {
+                halt!("Invalid destination {} in recipe {}. Destinations must be absolute paths inside the image.", file.dest, path);
+            }
+            if !valid_mode(&file.mode) {
+                halt!("Invalid mode {} for {} in recipe {}. Modes must be three octal digits, such as 644.", file.mode, file.dest, path);
+            }
+        }
+        recipe
+    }
+
+    pub fn options(&self, builder: &dyn ImageBuilder) -> Options {
+        let mut options = Options::new(builder, Choices {
+            release: self.release.clone(),
+            arch: self.arch.clone(),
+            mirror: self.mirror.clone(),
+            security_mirror: self.security_mirror.clone(),
+            components: self.components.clone(),
+            snapshot: self.snapshot.clone(),
+            bootstrapper: self.bootstrapper.clone(),
+            locales: self.locales.clone(),
+            timezone: self.timezone.clone(),
+            keyboard: self.keyboard.clone(),
+            console_font: self.console_font.clone(),
+            hostname: self.hostname.clone(),
+            backports: self.backports,
+        });
+        if let Some(name) = &self.name {
+            options.name = name.clone();
+        }
+        options
+    }
+}
+
+
+pub fn resolve(recipe_dir: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        recipe_dir.join(path)
+    }
+}
+
+/// Three octal digits, so recipes cannot ask for setuid, setgid or sticky files.
+fn valid_mode(mode: &str) -> bool {
+    mode.len() == 3 && mode.chars().all(|c| ('0'..='7').contains(&c))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@:".contains(c))
+}
+
+
+/// Variables available to file templates in image recipes.
+pub fn template_vars(options: &Options) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("image".to_owned(), options.name.clone()),
+        ("distro".to_owned(), options.distro.clone()),
+        ("release".to_owned(), options.release.clone()),
+        ("arch".to_owned(), options.arch.clone()),
+    ])
+}
+
+
+pub fn create(path: &str) {
+    let recipe = Recipe::load(path);
+    let recipe_dir = Path::new(path).parent().unwrap_or(Path::new(".")).to_path_buf();
+    let builder = image::builder(&recipe.distro).unwrap_or_else(|| halt!(
+        "Recipe {} uses unsupported distribution: {}\nSupported distributions are: {}",
+        path,
+        recipe.distro,
+        image::supported_distros()
+    ));
+
+    for name in recipe.services.enable.iter().chain(&recipe.services.disable) {
+        if !valid_name(name) {
+            halt!("Invalid service name {} in recipe {}", name, path);
+        }
+    }
+    for group in &recipe.groups {
+        if !access::valid_user_name(group) {
+            halt!("Invalid group name {} in recipe {}", group, path);
+        }
+    }
+    for user in &recipe.users {
+        user.check();
+    }
+    if let Some(ssh) = &recipe.ssh {
+        ssh.check();
+    }
+
+    let options = recipe.options(builder);
+    let recipe_path = fs::canonicalize(path).unwrap_or_else(|e| halt!("Could not resolve recipe path {}: {}", path, e));
+    log!("Creating image {} from recipe {}", options.name, path);
+    let image_path = image::create(builder, &options, Some(&recipe_path.to_string_lossy()));
+    apply(&recipe, &recipe_dir, builder, &image_path, &options);
+    snapshot_stage(&options.name, "recipe");
+    finish(&options.name);
+}
+
+
+pub fn apply(recipe: &Recipe, recipe_dir: &Path, builder: &dyn ImageBuilder, image_path: &str, options: &Options) {
+    if !recipe.packages.is_empty() {
+        let packages: Vec<&str> = recipe.packages.iter().map(|p| p.as_str()).collect();
+        builder.install_packages(image_path, options, &packages);
+    }
+
+    let vars = template_vars(options);
+    for file in &recipe.files {
+        let source = resolve(recipe_dir, &file.source);
+        let contents = fs::read_to_string(&source).unwrap_or_else(|e| halt!("Could not read recipe file {:?}: {}", source, e));
+        let contents = if file.template {
+            template::render(&source.to_string_lossy(), &contents, &vars)
+        } else {
+            contents
+        };
+        install_file(image_path, &file.dest, &file.mode, &contents);
+    }
+
+    let systemctl = image_which(image_path, "systemctl");
+    for service in &recipe.services.enable {
+        perform(&format!("Enable {}", service), None, chroot(image_path, &[&systemctl, "enable", service]), true);
+    }
+    for service in &recipe.services.disable {
+        perform(&format!("Disable {}", service), None, chroot(image_path, &[&systemctl, "disable", service]), true);
+    }
+
+    for group in &recipe.groups {
+        perform(&format!("Add group {}", group), None, chroot(image_path, &[&image_which(image_path, "groupadd"), group]), true);
+    }
+    for user in &recipe.users {
+        if user.name != "root" {
+            let useradd = image_which(image_path, "useradd");
+            let groups = user.groups.join(",");
+            let mut args = vec![&useradd[..], "-m"];
+            if let Some(shell) = &user.shell {
+                args.extend_from_slice(&["-s", shell]);
+            }
+            if !groups.is_empty() {
+                args.extend_from_slice(&["-G", &groups]);
+            }
+            args.push(&user.name);
+            perform(&format!("Add user {}", user.name), None, chroot(image_path, &args), true);
+        }
+    }
+    apply_access(recipe, builder, image_path, options);
+
+    run_hooks(recipe, recipe_dir, image_path, options);
+}
+
+
+/// Run the recipe's hooks in order through `exec::run_script`, skipping any that have
+/// already run to completion with the same content.
+pub fn run_hooks(recipe: &Recipe, recipe_dir: &Path, image_path: &str, options: &Options) {
+    for hook in &recipe.hooks {
+        let source = resolve(recipe_dir, hook);
+        let script = fs::read_to_string(&source).unwrap_or_else(|e| halt!("Could not read hook script {:?}: {}", source, e));
+        let file_name = source.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_else(|| halt!("Invalid hook script path {}", hook));
+        if exec::applied(&options.name, &file_name, &manifest::sha256_hex(script.as_bytes())) {
+            log!("Hook {} was already applied to image {}, skipping.", file_name, options.name);
+            continue;
+        }
+        exec::run_script(image_path, options, "hook", &file_name, &script);
+    }
+}
+
+
+/// Install sshd and sudo as needed, then the users' keys, sudo rules and the sshd policy.
+fn apply_access(recipe: &Recipe, builder: &dyn ImageBuilder, image_path: &str, options: &Options) {
+    let has_keys = recipe.users.iter().any(|u| !u.authorized_keys.is_empty());
+    let has_sudo = recipe.users.iter().any(|u| u.sudo.is_some());
+    let mut packages = Vec::new();
+    if has_keys || recipe.ssh.is_some() {
+        packages.push("openssh-server");
+    }
+    if has_sudo {
+        packages.push("sudo");
+    }
+    if !packages.is_empty() {
+        builder.install_packages(image_path, options, &packages);
+    }
+
+    for user in &recipe.users {
+        if !user.authorized_keys.is_empty() {
+            let keys = format!("{}\n", user.authorized_keys.join("\n"));
+            install_file(image_path, &access::authorized_keys_path(&user.name), "644", &keys);
+        }
+        if let Some(rule) = &user.sudo {
+            let sudoers_path = access::sudoers_path("image", &user.name);
+            install_file(image_path, &sudoers_path, "440", access::sudoers(&user.name, rule));
+            perform(
+                &format!("Check sudo rule for {}", user.name),
+                None,
+                chroot(image_path, &[&image_which(image_path, "visudo"), "-cf", &sudoers_path]),
+                true
+            );
+        }
+    }
+
+    if has_keys || recipe.ssh.is_some() {
+        let ssh = recipe.ssh.clone().unwrap_or_default();
+        install_file(image_path, access::SSHD_CONFIG_PATH, "644", ssh.sshd_config());
+        perform("Enable ssh", None, chroot(image_path, &[&image_which(image_path, "systemctl"), "enable", "ssh"]), true);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        for mode in ["644", "755", "440", "000", "777"] {
+            assert!(valid_mode(mode), "{} rejected", mode);
+        }
+        for mode in ["", "64", "0644", "4755", "6755", "1777", "648", "rw-", "+644", "６44"] {
+            assert!(!valid_mode(mode), "{} accepted", mode);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::*;
+use crate::image::*;
+use crate::image::debian::*;
+
+const KEYRING: &str = "/usr/share/keyrings/ubuntu-archive-keyring.gpg";
+
+pub struct Ubuntu;
+
+impl ImageBuilder for Ubuntu {
+    fn distro(&self) -> &'static str {
+        "ubuntu"
+    }
+
+    /// LTS releases only
+    fn releases(&self) -> &'static [&'static str] {
+        &["jammy", "noble"]
+    }
+
+    fn default_release(&self) -> &'static str {
+        "noble"
+    }
+
+    /// Ubuntu serves non-x86 architectures from a separate ports archive.
+    fn default_mirror(&self, arch: &str) -> &'static str {
+        match arch {
+            "amd64" => "http://archive.ubuntu.com/ubuntu",
+            _ => "http://ports.ubuntu.com/ubuntu-ports",
+        }
+    }
+
+    fn default_components(&self) -> &'static [&'static str] {
+        &["main", "universe"]
+    }
+
+    fn mirror_key(&self) -> settings::Key {
+        settings::Key::UbuntuMirror
+    }
+
+    /// snapshot.ubuntu.com serves every suite, including security and ports, from one archive.
+    fn snapshot_mirrors(&self, snapshot: &str, arch: &str) -> (String, String) {
+        let archive = match arch {
+            "amd64" => "ubuntu",
+            _ => "ubuntu-ports",
+        };
+        let mirror = format!("https://snapshot.ubuntu.com/{}/{}", archive, snapshot);
+        (mirror.clone(), mirror)
+    }
+
+    fn bootstrap(&self, image_path: &str, options: &Options) {
+        if !std::path::Path::new(KEYRING).exists() {
+            halt!("Could not find the Ubuntu archive keyring {}. Please install the ubuntu-keyring package and try again.", KEYRING);
+        }
+        debian::bootstrap(image_path, options, Some(KEYRING));
+    }
+
+    fn configure_package_manager(&self, image_path: &str, options: &Options) {
+        let default_security_mirror = match options.arch.as_str() {
+            "amd64" => "http://security.ubuntu.com/ubuntu",
+            _ => &options.mirror,
+        };
+        let mut suites = vec![
+            (options.mirror.clone(), options.release.clone()),
+            (options.mirror.clone(), format!("{}-updates", options.release)),
+        ];
+        if let Some(security_mirror) = security_mirror(options, default_security_mirror) {
+            suites.push((security_mirror, format!("{}-security", options.release)));
+        }
+        if options.backports {
+            suites.push((options.mirror.clone(), options.backports_suite()));
+        }
+        write_sources(image_path, options, &suites);
+    }
+
+    fn configure_locale(&self, image_path: &str, options: &Options) {
+        configure_localisation(image_path, options);
+    }
+
+    /// Ubuntu kernels ship the ZFS module, so no headers or DKMS build are needed.
+    fn install_kernel(&self, image_path: &str, options: &Options) {
+        perform(
+            "Install packages",
+            None,
+            apt_install(
+                image_path,
+                options,
+                &[
+                    "keyboard-configuration",
+                    "console-setup",
+                    "linux-image-generic",
+                    "zfsutils-linux",
+                    "zfs-initramfs",
+                    "dosfstools",
+                ]
+            ),
+            true
+        );
+    }
+
+    fn enable_services(&self, image_path: &str, _options: &Options) {
+        enable_zfs_services(image_path);
+    }
+
+    fn install_packages(&self, image_path: &str, options: &Options, packages: &[&str]) {
+        perform("Install recipe packages", None, apt_install(image_path, options, packages), true);
+    }
+}
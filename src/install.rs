@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::Path;
+use crate::*;
+use crate::image::manifest::sha256_hex;
+use crate::machine::Machine;
+use crate::overlay::{MACHINES_DIR, ROOT_POOL};
+use serde::{Serialize, Deserialize};
+
+/// Container of the boot environments on a machine's root pool, which ZFSBootMenu scans.
+pub const BOOT_CONTAINER: &str = "ROOT";
+
+/// A machine's root pool, as recorded by 'ref install'.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pool {
+    pub name: String,
+    /// single, mirror or raidz
+    pub layout: String,
+}
+
+/// The root pool 'ref install' creates across the machine's disks.
+pub fn planned_pool(machine: &Machine) -> Pool {
+    let layout = match machine.disks.len() {
+        0 | 1 => "single",
+        2 => "mirror",
+        _ => "raidz",
+    };
+    Pool { name: ROOT_POOL.to_owned(), layout: layout.to_owned() }
+}
+
+
+/// The name a test VM's root pool is imported under on the host, so the pools of several
+/// machines, all called rpool, can be imported at once.
+pub fn host_pool(machine_name: &str) -> String {
+    format!("r-{}", machine_name)
+}
+
+/// Where the test VM's root pool is mounted on the host while it is imported.
+pub fn altroot(machine_name: &str) -> String {
+    format!("{}/{}/root", MACHINES_DIR, machine_name)
+}
+
+fn esp_mountpoint(machine_name: &str) -> String {
+    format!("{}/{}/esp", MACHINES_DIR, machine_name)
+}
+
+fn zvol_dir(machine: &Machine) -> String {
+    format!("/dev/zvol/{}/reflectron/{}", settings::disk_pool(), machine.name)
+}
+
+fn zvols(machine: &Machine) -> Vec<String> {
+    if machine.disks.is_empty() {
+        halt!("Machine {} has no disks", machine.name);
+    }
+    machine.disks.iter().map(|disk| format!("/dev/zvol/{}", disk::zvol_dataset(machine, disk))).collect()
+}
+
+
+fn is_imported(machine_name: &str) -> bool {
+    let mut command = zfs_query(&["list", &host_pool(machine_name)]);
+    command.stderr(std::process::Stdio::null());
+    success_stauts(command)
+}
+
+/// Import the test VM's root pool from its zvols, without mounting anything, and load its key.
+pub fn import(machine: &Machine) {
+    if vm::is_running(&machine.name) {
+        halt!("VM {} is running. Stop it before changing its disks.", machine.name);
+    }
+    if is_imported(&machine.name) {
+        return;
+    }
+    perform(
+        &format!("Import root pool of {}", machine.name),
+        None,
+        zpool(&["import", "-d", &zvol_dir(machine), "-N", "-R", &altroot(&machine.name), "-t", ROOT_POOL, &host_pool(&machine.name)]),
+        false
+    );
+    keys::load(machine, &format!("{}/{}", host_pool(&machine.name), BOOT_CONTAINER));
+    if let Some(state) = state::host_encryption_root(machine) {
+        keys::load(machine, &state);
+    }
+}
+
+/// Export the test VM's root pool so the VM can import it when it boots.
+pub fn export(machine: &Machine) {
+    if is_imported(&machine.name) {
+        perform(&format!("Export root pool of {}", machine.name), None, zpool(&["export", &host_pool(&machine.name)]), false);
+    }
+}
+
+
+/// Partition the test VM's disks, create its root pool and install its image as the first
+/// boot environment, with its overlay and ZFSBootMenu on every ESP. Wipes the VM's disks.
+pub fn install(machine: &Machine) {
+    let image_name = machine.image.as_deref().unwrap_or_else(|| halt!("Machine {} has no image. Create it with 'ref new --image'.", machine.name));
+    let image = image::get_image(image_name).unwrap_or_else(|| halt!("No image named {}", image_name));
+    if image.built.is_none() {
+        halt!("Image {} has not finished building", image_name);
+    }
+    if vm::is_running(&machine.name) {
+        halt!("VM {} is running. Stop it before installing it.", machine.name);
+    }
+    export(machine);
+
+    let zvols = zvols(machine);
+    for zvol in &zvols {
+        perform(&format!("Clear partition table of {}", zvol), None, pkexec(&[&which("sgdisk"), "--zap-all", zvol]), false);
+        perform(
+            &format!("Partition {}", zvol),
+            None,
+            pkexec(&[&which("sgdisk"), "-n1:1M:+512M", "-t1:EF00", "-n2:0:0", "-t2:BF00", zvol]),
+            false
+        );
+    }
+    let mut settle = std::process::Command::new(which("udevadm"));
+    settle.arg("settle");
+    perform("Wait for partitions", None, settle, false);
+    for zvol in &zvols {
+        for part in ["part1", "part2"] {
+            if !Path::new(&format!("{}-{}", zvol, part)).exists() {
+                halt!("Partition {}-{} did not appear", zvol, part);
+            }
+        }
+        perform(
+            &format!("Format ESP on {}", zvol),
+            None,
+            pkexec(&[&which("mkfs.vfat"), "-F", "32", "-n", "EFI", &format!("{}-part1", zvol)]),
+            false
+        );
+    }
+
+    let pool = host_pool(&machine.name);
+    let altroot = altroot(&machine.name);
+    let root_pool = planned_pool(machine);
+    let mut args = vec![
+        "create", "-f",
+        "-o", "ashift=12", "-o", "autotrim=on",
+        "-O", "compression=lz4", "-O", "acltype=posixacl", "-O", "xattr=sa", "-O", "relatime=on",
+        "-O", "canmount=off", "-O", "mountpoint=/",
+        "-R", &altroot, "-t", &pool, &root_pool.name,
+    ];
+    if root_pool.layout != "single" {
+        args.push(&root_pool.layout);
+    }
+    let partitions: Vec<String> = zvols.iter().map(|zvol| format!("{}-part2", zvol)).collect();
+    args.extend(partitions.iter().map(|p| p.as_str()));
+    perform(&format!("Create root pool of {}", machine.name), None, zpool(&args), false);
+    let mut machine = machine::get_machine(&machine.name).unwrap_or_else(|| halt!("No machine named {}", machine.name));
+    machine.pool = Some(root_pool);
+    machine::save_machine(&machine);
+    let machine = &machine;
+
+    let container = format!("{}/{}", pool, BOOT_CONTAINER);
+    let encryption = keys::create_options(machine);
+    let mut args = vec!["create", "-o", "canmount=off", "-o", "mountpoint=none"];
+    args.extend(encryption.iter().map(|o| o.as_str()));
+    args.push(&container);
+    perform("Create boot environment container", None, zfs(&args), false);
+    state::create_host(machine);
+
+    let environment = format!("{}/{}", container, image_name);
+    pipe(
+        &format!("Install image {} as boot environment {}", image_name, environment),
+        zfs(&["send", &format!("{}@built", image::image_dataset(image_name))]),
+        zfs(&["receive", "-u", "-o", "mountpoint=/", "-o", "canmount=noauto", &environment])
+    );
+    perform("Set boot environment", None, zpool(&["set", &format!("bootfs={}", environment), &pool]), false);
+
+    copy_overlay(machine, &environment);
+
+    keys::set_boot_keylocation(machine, &container);
+    let mut machine = machine::get_machine(&machine.name).unwrap_or_else(|| halt!("No machine named {}", machine.name));
+    machine.boot.vm_image = Some(update_boot(&machine));
+    machine::save_machine(&machine);
+    export(&machine);
+    log!("Installed image {} on the disks of {}", image_name, machine.name);
+}
+
+
+/// Render the machine's overlay and copy it into one of the test VM's boot environments. The
+/// pool must be imported.
+pub fn copy_overlay(machine: &Machine, environment: &str) {
+    let overlay = overlay::render(machine);
+    perform(&format!("Mount {}", environment), None, zfs(&["mount", environment]), false);
+    perform(
+        &format!("Copy overlay into {}", environment),
+        None,
+        pkexec(&[&which("cp"), "-R", "--preserve=mode", &format!("{}/.", overlay), &format!("{}/", altroot(&machine.name))]),
+        false
+    );
+    perform(&format!("Unmount {}", environment), None, zfs(&["umount", environment]), false);
+}
+
+
+/// Set the ZFSBootMenu properties of the test VM's pool and install ZFSBootMenu on each of its
+/// ESPs, checking they all hold the same image afterwards. The pool must be imported.
+/// Returns the checksum of the image installed.
+pub fn update_boot(machine: &Machine) -> String {
+    zbm::check_commandline(&machine.boot.commandline);
+    let container = format!("{}/{}", host_pool(&machine.name), BOOT_CONTAINER);
+    perform(
+        "Set ZFSBootMenu command line",
+        None,
+        zfs(&["set", &format!("org.zfsbootmenu:commandline={}", machine.boot.commandline), &container]),
+        false
+    );
+
+    let efi = zbm::efi_image(machine);
+    let checksum = sha256_hex(&efi);
+    let esp = esp_mountpoint(&machine.name);
+    fs::create_dir_all(&esp).unwrap_or_else(|e| halt!("Could not create ESP mountpoint {}: {}", esp, e));
+
+    for zvol in zvols(machine) {
+        let partition = format!("{}-part1", zvol);
+        perform(&format!("Mount ESP {}", partition), None, pkexec(&[&which("mount"), "-t", "vfat", &partition, &esp]), false);
+        zbm::install_local(&esp, &efi);
+        let installed = [zbm::EFI_PATH, zbm::FALLBACK_EFI_PATH].iter()
+            .map(|path| fs::read(format!("{}{}", esp, path)).map(|data| sha256_hex(&data)).unwrap_or_default())
+            .collect::<Vec<_>>();
+        perform(&format!("Unmount ESP {}", partition), None, pkexec(&[&which("umount"), &esp]), false);
+        if installed.iter().any(|c| *c != checksum) {
+            halt!("ZFSBootMenu on ESP {} of {} does not match the image installed", partition, machine.name);
+        }
+    }
+    checksum
+}
+
+
+/// Install ZFSBootMenu on the ESPs of the test VM, without touching its boot environments.
+pub fn update_vm_boot(machine: &Machine) {
+    import(machine);
+    let mut machine = machine::get_machine(&machine.name).unwrap_or_else(|| halt!("No machine named {}", machine.name));
+    machine.boot.vm_image = Some(update_boot(&machine));
+    machine::save_machine(&machine);
+    export(&machine);
+    log!("Installed ZFSBootMenu on the ESPs of VM {}", machine.name);
+}
@@ -0,0 +1,322 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+use crate::*;
+use crate::machine::Machine;
+
+/// Wrapping keys of machines' encryption roots. Only the reflectron group can enter it, and
+/// key files are readable by their owner alone.
+pub const KEYS_DIR: &str = "/opt/reflectron/keys";
+
+const KEYFORMATS: &[&str] = &["passphrase", "hex", "raw"];
+const PASSPHRASE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789";
+const PASSPHRASE_LENGTH: usize = 32;
+
+
+/// Native encryption of a machine's root pool. Boot environments live below the encryption
+/// root `rpool/ROOT`, and are replicated raw so their keys are never sent with them. State datasets have
+/// their own encryption root, `rpool/reflectron/state`, with the same key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Encryption {
+    /// passphrase, hex or raw
+    pub keyformat: String,
+    /// Where the production machine reads the key from when it boots: prompt, for ZFSBootMenu
+    /// to ask for it on the console or over SSH, or a file:// URL on the machine
+    pub keylocation: String,
+    pub created: String,
+    #[serde(default)]
+    pub rotated: Option<String>,
+}
+
+
+pub fn key_path(machine_name: &str) -> String {
+    format!("{}/{}.key", KEYS_DIR, machine_name)
+}
+
+/// The key as zfs reads it on the host.
+pub fn key_url(machine_name: &str) -> String {
+    format!("file://{}", key_path(machine_name))
+}
+
+
+fn check_policy(keyformat: &str, keylocation: &str) {
+    if !KEYFORMATS.contains(&keyformat) {
+        halt!("Invalid keyformat {}. Valid formats are: {}", keyformat, KEYFORMATS.join(", "));
+    }
+    let file = keylocation.strip_prefix("file:///")
+        .is_some_and(|path| !path.contains("..") && path.chars().all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c)));
+    if keylocation != "prompt" && !file {
+        halt!("Invalid keylocation {}. Use prompt or a file:///<path> URL.", keylocation);
+    }
+    if keyformat != "passphrase" && keylocation == "prompt" {
+        halt!("A {} key cannot be typed at a prompt. Give a file:///<path> keylocation for it.", keyformat);
+    }
+}
+
+
+fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; count];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .unwrap_or_else(|e| halt!("Could not read random bytes: {}", e));
+    bytes
+}
+
+/// A new random key in the given format.
+fn generate(keyformat: &str) -> Vec<u8> {
+    match keyformat {
+        "raw" => random_bytes(32),
+        "hex" => random_bytes(32).iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes(),
+        _ => {
+            // rejection sampling keeps the characters uniformly distributed
+            let limit = 256 - 256 % PASSPHRASE_CHARS.len();
+            let mut passphrase = Vec::with_capacity(PASSPHRASE_LENGTH);
+            while passphrase.len() < PASSPHRASE_LENGTH {
+                let byte = random_bytes(1)[0] as usize;
+                if byte < limit {
+                    passphrase.push(PASSPHRASE_CHARS[byte % PASSPHRASE_CHARS.len()]);
+                }
+            }
+            passphrase
+        }
+    }
+}
+
+fn write_key(path: &str, key: &[u8]) {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .unwrap_or_else(|e| halt!("Could not create key file {}: {}", path, e));
+    file.write_all(key).unwrap_or_else(|e| halt!("Could not write key file {}: {}", path, e));
+}
+
+
+/// Generate a machine's key and record its encryption policy. The pool is encrypted when the
+/// machine is next installed with 'ref install'.
+pub fn create(machine_name: &str, keyformat: &str, keylocation: &str) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    if machine.encryption.is_some() {
+        halt!("Machine {} already has a key. Use 'ref key rotate {}' to replace it.", machine_name, machine_name);
+    }
+    check_policy(keyformat, keylocation);
+    if !Path::new(KEYS_DIR).is_dir() {
+        halt!("Key directory {} does not exist. Run setup.sh to create it.", KEYS_DIR);
+    }
+
+    write_key(&key_path(machine_name), &generate(keyformat));
+    machine.encryption = Some(Encryption {
+        keyformat: keyformat.to_owned(),
+        keylocation: keylocation.to_owned(),
+        created: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        rotated: None,
+    });
+    machine::save_machine(&machine);
+    log!("Created {} key for {} at {}. Run 'ref install {}' to encrypt its pool.", keyformat, machine_name, key_path(machine_name), machine_name);
+}
+
+
+/// The options creating a machine's encryption root on the host, with the key loaded.
+pub fn create_options(machine: &Machine) -> Vec<String> {
+    match &machine.encryption {
+        Some(encryption) => vec![
+            "-o".to_owned(), "encryption=aes-256-gcm".to_owned(),
+            "-o".to_owned(), format!("keyformat={}", encryption.keyformat),
+            "-o".to_owned(), format!("keylocation={}", key_url(&machine.name)),
+        ],
+        None => Vec::new(),
+    }
+}
+
+
+fn keylocation(encryption_root: &str) -> String {
+    get(zfs_query(&["get", "-H", "-o", "value", "keylocation", encryption_root])).trim().to_owned()
+}
+
+fn new_key_path(machine_name: &str) -> String {
+    format!("{}.new", key_path(machine_name))
+}
+
+/// The key file unlocking one of the test VM's encryption roots on the host: the machine's
+/// key, or the new key of a rotation interrupted after the root was changed to it.
+fn host_key_url(machine: &Machine, encryption_root: &str) -> String {
+    let new_path = new_key_path(&machine.name);
+    let new_url = format!("file://{}", new_path);
+    if Path::new(&new_path).exists() && keylocation(encryption_root) == new_url {
+        new_url
+    } else {
+        key_url(&machine.name)
+    }
+}
+
+
+/// Load the key of the test VM's encryption root on the host. The pool must be imported.
+pub fn load(machine: &Machine, encryption_root: &str) {
+    if machine.encryption.is_none() {
+        return;
+    }
+    let mut status = zfs_query(&["get", "-H", "-o", "value", "keystatus", encryption_root]);
+    status.stderr(std::process::Stdio::null());
+    if get(status).trim() == "available" {
+        return;
+    }
+    perform(
+        &format!("Load key of {}", encryption_root),
+        None,
+        zfs(&["load-key", "-L", &host_key_url(machine, encryption_root), encryption_root]),
+        false
+    );
+}
+
+
+/// Point the encryption root at where the production machine reads its key. Until then the
+/// host's key file is used.
+pub fn set_boot_keylocation(machine: &Machine, encryption_root: &str) {
+    if let Some(encryption) = &machine.encryption {
+        perform(
+            &format!("Set key location of {}", encryption_root),
+            None,
+            zfs(&["set", &format!("keylocation={}", encryption.keylocation), encryption_root]),
+            false
+        );
+    }
+}
+
+
+/// Whether the machine's datasets must be sent raw, so they arrive encrypted with the keys
+/// left behind.
+pub fn raw_send(machine: &Machine) -> bool {
+    machine.encryption.is_some()
+}
+
+
+/// Change the wrapping key of production's encryption roots to the machine's key, typed into
+/// a prompt over SSH, and install the key at its key location if that is a file. The keys must
+/// be loaded.
+fn rotate_production(machine: &Machine, encryption: &Encryption) {
+    let (sess, address) = machine::session(machine);
+    let datasets = [deploy::receive_dataset(), format!("{}/{}", overlay::ROOT_POOL, state::STATE_CONTAINER)];
+    let mut encryption_roots: Vec<String> = Vec::new();
+    for dataset in &datasets {
+        let root = machine::remote_run(&sess, &address, "Get encryption root", &format!(
+            "zfs get -H -o value encryptionroot {} 2>/dev/null || true", dataset
+        ));
+        let root = root.trim();
+        if !root.is_empty() && root != "-" && !encryption_roots.iter().any(|r| r == root) {
+            encryption_roots.push(root.to_owned());
+        }
+    }
+
+    let mut key = fs::read(key_path(&machine.name)).unwrap_or_else(|e| halt!("Could not read key {}: {}", key_path(&machine.name), e));
+    for encryption_root in &encryption_roots {
+        let status = machine::remote_run(&sess, &address, "Get key status", &format!("zfs get -H -o value keystatus {}", encryption_root));
+        if status.trim() != "available" {
+            halt!("The key of {} on {} is not loaded. Load it with 'zfs load-key {}' and run 'ref key rotate {}' again.", encryption_root, machine.name, encryption_root, machine.name);
+        }
+    }
+    if encryption.keyformat == "passphrase" {
+        key.push(b'\n');
+    }
+    for encryption_root in &encryption_roots {
+        machine::remote_run_with_input(&sess, &address, &format!("Change key of {}", encryption_root), &format!(
+            "zfs change-key -o keylocation=prompt -o keyformat={} {}", encryption.keyformat, encryption_root
+        ), &key);
+    }
+    // until the file holds the new key, the roots ask for it at a prompt
+    if let Some(path) = encryption.keylocation.strip_prefix("file://") {
+        let key = fs::read(key_path(&machine.name)).unwrap_or_else(|e| halt!("Could not read key {}: {}", key_path(&machine.name), e));
+        machine::remote_write(&sess, &address, &format!("{}.new", path), 0o600, &key);
+        machine::remote_run(&sess, &address, &format!("Install key at {}", path), &format!("mv -f {0}.new {0}", path));
+        for encryption_root in &encryption_roots {
+            machine::remote_run(&sess, &address, &format!("Set key location of {}", encryption_root), &format!(
+                "zfs set keylocation={} {}", encryption.keylocation, encryption_root
+            ));
+        }
+    }
+}
+
+
+/// Replace the wrapping key of the test VM's encryption roots with a new one, and then of
+/// production's if it has been deployed to. The old key is kept, named after the time it was
+/// retired. A new key left by an interrupted rotation is used again if any encryption root was
+/// already changed to it.
+pub fn rotate(machine_name: &str) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let encryption = machine.encryption.clone()
+        .unwrap_or_else(|| halt!("Machine {} has no key. Create one with 'ref key create {}'.", machine_name, machine_name));
+
+    install::import(&machine);
+    let mut encryption_roots = vec![format!("{}/{}", install::host_pool(machine_name), install::BOOT_CONTAINER)];
+    encryption_roots.extend(state::host_encryption_root(&machine));
+
+    let path = key_path(machine_name);
+    let new_path = new_key_path(machine_name);
+    let new_url = format!("file://{}", new_path);
+    let changed: Vec<bool> = encryption_roots.iter().map(|root| keylocation(root) == new_url).collect();
+    if Path::new(&new_path).exists() && (changed.contains(&true) || !Path::new(&path).exists()) {
+        log!("Resuming the interrupted rotation of the key of {} with {}", machine_name, new_path);
+    } else {
+        if Path::new(&new_path).exists() {
+            fs::remove_file(&new_path).unwrap_or_else(|e| halt!("Could not remove unused key {}: {}", new_path, e));
+        }
+        write_key(&new_path, &generate(&encryption.keyformat));
+    }
+    for (encryption_root, _) in encryption_roots.iter().zip(&changed).filter(|(_, changed)| !**changed) {
+        perform(
+            &format!("Change key of {}", encryption_root),
+            None,
+            zfs(&[
+                "change-key",
+                "-o", &format!("keylocation=file://{}", new_path),
+                "-o", &format!("keyformat={}", encryption.keyformat),
+                encryption_root,
+            ]),
+            false
+        );
+    }
+
+    let now = Local::now();
+    // an interrupted rotation may have retired the old key already
+    let retired = format!("{}.{}", path, now.format("%Y%m%dT%H%M%S"));
+    if Path::new(&path).exists() {
+        fs::rename(&path, &retired).unwrap_or_else(|e| halt!("Could not keep old key as {}: {}", retired, e));
+        log!("Kept the previous key of {} at {}", machine_name, retired);
+    }
+    fs::rename(&new_path, &path).unwrap_or_else(|e| halt!("Could not install new key {}: {}", path, e));
+    for encryption_root in &encryption_roots {
+        set_boot_keylocation(&machine, encryption_root);
+    }
+    install::export(&machine);
+
+    if let Some(encryption) = machine.encryption.as_mut() {
+        encryption.rotated = Some(now.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    machine::save_machine(&machine);
+    log!("Rotated key of the test VM of {}", machine_name);
+
+    if deploy::last_deployment(machine_name).is_none() {
+        log!("Nothing has been deployed to {} yet, so its first deploy brings the new key.", machine_name);
+        return;
+    }
+    rotate_production(&machine, &encryption);
+    log!("Rotated key of {} on its test VM and in production", machine_name);
+}
+
+
+pub fn show(machine_name: &str) {
+    let machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    match &machine.encryption {
+        Some(encryption) => {
+            println!("Key format:   {}", encryption.keyformat);
+            println!("Key location: {}", encryption.keylocation);
+            println!("Key file:     {}", key_path(machine_name));
+            println!("Created:      {}", encryption.created);
+            println!("Rotated:      {}", encryption.rotated.as_deref().unwrap_or("never"));
+        }
+        None => println!("Machine {} is not encrypted", machine_name),
+    }
+}
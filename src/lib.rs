@@ -1,9 +1,24 @@
+pub mod access;
+pub mod data;
+pub mod deploy;
 pub mod disk;
+pub mod drift;
+pub mod environment;
 pub mod image;
+pub mod install;
+pub mod keys;
 pub mod machine;
+pub mod nic;
+pub mod overlay;
 pub mod settings;
-
-use std::fs::OpenOptions;
+pub mod state;
+pub mod template;
+pub mod topology;
+pub mod vm;
+pub mod zbm;
+
+use std::fs::{File, OpenOptions};
+use std::sync::{Arc, Mutex};
 use std::path::Path;
 use chrono::Local;
 use std::path::PathBuf;
@@ -101,6 +116,27 @@ pub fn zfs(args: &[&str]) -> Command {
     pkexec(&zfs_args)
 }
 
+pub fn zpool(args: &[&str]) -> Command {
+    let zpool_path = which("zpool");
+    let mut zpool_args = vec![&zpool_path[..]];
+    zpool_args.extend_from_slice(args);
+    pkexec(&zpool_args)
+}
+
+/// Unprivileged zfs command for read-only queries such as `zfs list` and `zfs get`.
+pub fn zfs_query(args: &[&str]) -> Command {
+    let mut command = Command::new(which("zfs"));
+    command.args(args);
+    command
+}
+
+/// Unprivileged zpool command for read-only queries such as `zpool get`.
+pub fn zpool_query(args: &[&str]) -> Command {
+    let mut command = Command::new(which("zpool"));
+    command.args(args);
+    command
+}
+
 pub fn success_stauts(mut command: Command) -> bool {
     match command.output() {
         Ok(output) => {
@@ -116,58 +152,12 @@ pub fn perform(description: &str, check: Option<Command>, mut operation: Command
     if let Some(check_cmd) = check {
         if success_stauts(check_cmd) {
             log!("{} was already done, skipping.", description);
+            return;
         }
     }
 
     if stream_output {
-        operation.stdout(Stdio::piped());
-        operation.stderr(Stdio::piped());
-
-        let mut child = match operation.spawn() {
-            Ok(child) => child,
-            Err(e) => {
-                halt!("Failed to spawn command '{}': {}", operation.cmdline(), e);
-            }
-        };
-
-        let stdout = child.stdout.take().expect("Failed to capture stdout");
-        let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-        let stdout_handle = thread::spawn(move || {
-            let mut reader = BufReader::new(stdout);
-            let mut buffer = Vec::new();
-            while reader.read_until(b'\n', &mut buffer).unwrap_or(0) > 0 {
-                print!("{}", String::from_utf8_lossy(&buffer));
-                buffer.clear();
-            }
-        });
-
-        let stderr_handle = thread::spawn(move || {
-            let reader = BufReader::new(stderr);
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    eprintln!("{}", line);
-                }
-            }
-        });
-
-        // Wait for the command to finish
-        match child.wait() {
-            Ok(status) => {
-                // Wait for output threads to finish
-                stdout_handle.join().expect("Stdout thread panicked");
-                stderr_handle.join().expect("Stderr thread panicked");
-
-                if status.success() {
-                    log!("{} succeeded.", description);
-                } else {
-                    halt!("Command '{}' failed with exit code: {:?}", operation.cmdline(), status.code());
-                }
-            },
-            Err(e) => {
-                halt!("Failed to wait for command '{}': {}", operation.cmdline(), e);
-            }
-        }
+        stream(description, &mut operation, None);
     } else {
         // Original non-streaming behavior
         match operation.output() {
@@ -189,6 +179,93 @@ pub fn perform(description: &str, check: Option<Command>, mut operation: Command
 }
 
 
+/// Like `perform` with streamed output, also copying the output to `log_path`.
+pub fn perform_logged(description: &str, mut operation: Command, log_path: &str) {
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .unwrap_or_else(|e| halt!("Could not open log file {}: {}", log_path, e));
+    stream(description, &mut operation, Some(Arc::new(Mutex::new(log_file))));
+}
+
+fn stream(description: &str, operation: &mut Command, log_file: Option<Arc<Mutex<File>>>) {
+    operation.stdout(Stdio::piped());
+    operation.stderr(Stdio::piped());
+
+    let mut child = match operation.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            halt!("Failed to spawn command '{}': {}", operation.cmdline(), e);
+        }
+    };
+
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stderr = child.stderr.take().expect("Failed to capture stderr");
+
+    let stdout_log = log_file.clone();
+    let stdout_handle = thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut buffer = Vec::new();
+        while reader.read_until(b'\n', &mut buffer).unwrap_or(0) > 0 {
+            print!("{}", String::from_utf8_lossy(&buffer));
+            if let Some(log) = &stdout_log {
+                let _ = log.lock().map(|mut file| file.write_all(&buffer));
+            }
+            buffer.clear();
+        }
+    });
+
+    let stderr_log = log_file;
+    let stderr_handle = thread::spawn(move || {
+        let reader = BufReader::new(stderr);
+        for line in reader.lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+            if let Some(log) = &stderr_log {
+                let _ = log.lock().map(|mut file| writeln!(file, "{}", line));
+            }
+        }
+    });
+
+    // Wait for the command to finish
+    match child.wait() {
+        Ok(status) => {
+            // Wait for output threads to finish
+            stdout_handle.join().expect("Stdout thread panicked");
+            stderr_handle.join().expect("Stderr thread panicked");
+
+            if status.success() {
+                log!("{} succeeded.", description);
+            } else {
+                halt!("Command '{}' failed with exit code: {:?}", operation.cmdline(), status.code());
+            }
+        },
+        Err(e) => {
+            halt!("Failed to wait for command '{}': {}", operation.cmdline(), e);
+        }
+    }
+}
+
+
+/// Run `source` with its output piped into `sink`, e.g. a zfs send into a zfs receive.
+pub fn pipe(description: &str, mut source: Command, mut sink: Command) {
+    source.stdout(Stdio::piped());
+    let mut source_child = source.spawn().unwrap_or_else(|e| halt!("Failed to spawn command '{}': {}", source.cmdline(), e));
+    let stdout = source_child.stdout.take().expect("Failed to capture stdout");
+    sink.stdin(stdout);
+
+    let sink_status = sink.status().unwrap_or_else(|e| halt!("Failed to run command '{}': {}", sink.cmdline(), e));
+    let source_status = source_child.wait().unwrap_or_else(|e| halt!("Failed to wait for command '{}': {}", source.cmdline(), e));
+    if !source_status.success() {
+        halt!("Command '{}' failed with exit code: {:?}", source.cmdline(), source_status.code());
+    }
+    if !sink_status.success() {
+        halt!("Command '{}' failed with exit code: {:?}", sink.cmdline(), sink_status.code());
+    }
+    log!("{} succeeded.", description);
+}
+
+
 trait CommandExt {
     fn cmdline(&self) -> String;
 }
@@ -1,9 +1,14 @@
-use ssh2::Session;
-use std::io::prelude::*;
+use ssh2::{Channel, Session};
+use std::io::{self, prelude::*};
 use std::net::TcpStream;
 use crate::*;
-use crate::settings::Key;
 use crate::disk::Disk;
+use crate::nic::Nic;
+use crate::access::Access;
+use crate::zbm::Boot;
+use crate::keys::Encryption;
+use crate::state::State;
+use crate::install::Pool;
 use ron::ser::{to_string_pretty, PrettyConfig};
 use serde::{Serialize, Deserialize};
 
@@ -11,43 +16,76 @@ use serde::{Serialize, Deserialize};
 pub struct Machine {
     pub name: String,
     pub disks: Vec<Disk>,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub nics: Vec<Nic>,
+    /// Image the machine's root is built from
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Keys, sudo rules and sshd policy the machine adds to its image
+    #[serde(default)]
+    pub access: Access,
+    /// How ZFSBootMenu boots the machine
+    #[serde(default)]
+    pub boot: Boot,
+    /// Native encryption of the machine's root pool
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    /// Writable mounts on top of the machine's read-only root
+    #[serde(default)]
+    pub state: State,
+    /// The root pool, once 'ref install' has created it
+    #[serde(default)]
+    pub pool: Option<Pool>,
 }
 
 fn machines_db() -> sled::Tree {
     database().open_tree("machines").unwrap_or_else(|e| halt!("Could not open machines database tree: {}", e))
 }
 
-pub fn new(machine_name: &str, ip: &str, password: &str) {
+pub fn new(machine_name: &str, ip: &str, password: &str, image: Option<&str>) {
+    // machine zvols share <pool>/reflectron with the images dataset
+    if machine_name == "images" {
+        halt!("A machine cannot be named images, the name of the images dataset");
+    }
+
     let db = machines_db();
 
     if db.contains_key(machine_name).unwrap_or_else(|e| halt!("Error checking if machine exists: {}", e)) {
         halt!("Machine {} already exists in the database", machine_name);
     }
 
-    let zpool = settings::get(Key::DiskPool).unwrap_or_else(|| halt!("Reflectron property disk-pool has not been set. Use 'ref set disk-pool <poolname>' to set it, and retry this command."));
+    if let Some(image) = image {
+        if image::get_image(image).is_none() {
+            halt!("No image named {}. Use 'ref image list' to see the available images.", image);
+        }
+    }
+
+    let zpool = settings::disk_pool();
     let zvol_path = format!("{}/reflectron/{}", zpool, machine_name);
     if success_stauts(zfs(&["list", &zvol_path])) {
         halt!("ZFS dataset {} already exists - refusing to overwrite it by creating new machine {}", zvol_path, machine_name);
     }
 
-    let disks = disk::parse_output(&get_disk_info(ip, password));
+    let sess = connect(ip, password);
+    println!("Connected to remote server. Getting disk info...");
+    let disks = disk::parse_output(&remote_output(&sess, ip, disk::DISK_INFO));
+    println!("Getting network interface info...");
+    let nics = nic::parse_output(&remote_output(&sess, ip, nic::NIC_INFO));
     let machine = Machine {
         name: machine_name.to_string(),
         disks,
+        address: Some(ip.to_string()),
+        nics,
+        image: image.map(|i| i.to_string()),
+        access: Access::default(),
+        boot: Boot::default(),
+        encryption: None,
+        state: State::default(),
+        pool: None,
     };
-
-    let config = PrettyConfig::new()
-        .struct_names(true)
-        .compact_arrays(false);
-        
-    let machine_data = to_string_pretty(&machine, config)
-        .unwrap_or_else(|e| halt!("Could not serialize data: {}", e));
-
-    db.insert(
-        machine_name.as_bytes(),
-        machine_data.as_bytes()
-    ).unwrap_or_else(|e| halt!("Could not insert data: {}", e));  
-    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+    save_machine(&machine);
 
     println!("Machine: {}", machine_name);
     println!("-------------------");
@@ -57,33 +95,178 @@ pub fn new(machine_name: &str, ip: &str, password: &str) {
         println!("{}", disk);
         println!("-------------------");
     }
+    for nic in &machine.nics {
+        println!("{}", nic);
+        println!("-------------------");
+    }
 
     // simulate disks as ZVOLs
     disk::create_zvols(&machine);
 }
 
-pub fn get_disk_info(ip: &str, password: &str) -> String {
-    // SSH connection and command execution
+pub fn connect(ip: &str, password: &str) -> Session {
     let tcp = TcpStream::connect(ip).unwrap_or_else(|e| halt!("Could not open TCP connection to remote machine {} : {}", ip, e));
     let mut sess = Session::new().unwrap_or_else(|e| halt!("Could not create SSH session: {}", e));
     sess.set_tcp_stream(tcp);
     sess.handshake().unwrap_or_else(|e| halt!("Could not perform SSH handshake with remote machine at {} : {}", ip, e));
 
     sess.userauth_password("root", password).unwrap_or_else(|e| halt!("Authentication failed for SSH user root@{} : {}", ip, e));
+    sess
+}
 
-    println!("Connected to remote server. Getting disk info...");
+/// An SSH session to the machine's production address, authenticated as root through the
+/// user's ssh-agent.
+pub fn session(machine: &Machine) -> (Session, String) {
+    let address = machine.address.clone().unwrap_or_else(|| halt!("Machine {} has no production address", machine.name));
+    (agent_session(&machine.name, &address), address)
+}
 
+/// An SSH session as root to `address`, authenticated through the user's ssh-agent.
+pub fn agent_session(machine_name: &str, address: &str) -> Session {
+    let tcp = TcpStream::connect(address).unwrap_or_else(|e| halt!("Could not open TCP connection to remote machine {} : {}", address, e));
+    let mut sess = Session::new().unwrap_or_else(|e| halt!("Could not create SSH session: {}", e));
+    sess.set_tcp_stream(tcp);
+    sess.handshake().unwrap_or_else(|e| halt!("Could not perform SSH handshake with remote machine at {} : {}", address, e));
+
+    sess.userauth_agent("root").unwrap_or_else(|e| halt!(
+        "Authentication failed for SSH user root@{} : {}. Add a key authorized for root on {} to your ssh-agent.",
+        address, e, machine_name
+    ));
+    sess
+}
+
+/// Run a command on the remote machine, halting if it fails, and return its output.
+pub fn remote_run(sess: &Session, address: &str, description: &str, command: &str) -> String {
+    remote_run_with_input(sess, address, description, command, &[])
+}
+
+/// Like `remote_run`, writing `input` to the command's standard input.
+pub fn remote_run_with_input(sess: &Session, address: &str, description: &str, command: &str, input: &[u8]) -> String {
+    let mut channel = sess.channel_session().unwrap_or_else(|e| halt!("Could not open SSH channtel to {} : {}", address, e));
+    channel.exec(command).unwrap_or_else(|e| halt!("SSH command failed: {}", e));
+    channel.write_all(input).unwrap_or_else(|e| halt!("Could not write input of '{}' on {}: {}", description, address, e));
+    channel.send_eof().unwrap_or_else(|e| halt!("Could not write input of '{}' on {}: {}", description, address, e));
+
+    let (output, errors) = read_outputs(sess, &mut channel).unwrap_or_else(|e| halt!("Could not read SSH command output: {}", e));
+    channel.wait_close().unwrap_or_else(|e| halt!("Could not close SSH channel to {}: {}", address, e));
+    let status = channel.exit_status().unwrap_or_else(|e| halt!("Could not get exit status of '{}' on {}: {}", command, address, e));
+    if status != 0 {
+        halt!("{} failed on {} with exit code {}:\n{}{}", description, address, status, output, errors);
+    }
+    log!("{} on {} succeeded.", description, address);
+    output
+}
+
+/// Read a channel's standard output and error to the end. Both share the channel's window,
+/// so a command that writes a lot to one would stall while the other is read to the end.
+pub fn read_outputs(sess: &Session, channel: &mut Channel) -> io::Result<(String, String)> {
+    sess.set_blocking(false);
+    let result = read_interleaved(channel);
+    sess.set_blocking(true);
+    let (output, errors) = result?;
+    Ok((String::from_utf8_lossy(&output).into_owned(), String::from_utf8_lossy(&errors).into_owned()))
+}
+
+fn read_interleaved(channel: &mut Channel) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut streams = [Vec::new(), Vec::new()];
+    let mut done = [false, false];
+    let mut buffer = [0; 32768];
+    while !(done[0] && done[1]) {
+        let mut idle = true;
+        for id in 0..2 {
+            if done[id] {
+                continue;
+            }
+            match channel.stream(id as i32).read(&mut buffer) {
+                Ok(0) => done[id] = true,
+                Ok(n) => {
+                    streams[id].extend_from_slice(&buffer[..n]);
+                    idle = false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if idle {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    let [output, errors] = streams;
+    Ok((output, errors))
+}
+
+/// Like `agent_session`, for machines that may be down or rebooting: None instead of halting.
+pub fn try_agent_session(address: &str) -> Option<Session> {
+    let socket = std::net::ToSocketAddrs::to_socket_addrs(address).ok()?.next()?;
+    let tcp = TcpStream::connect_timeout(&socket, std::time::Duration::from_secs(5)).ok()?;
+    let mut sess = Session::new().ok()?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake().ok()?;
+    sess.userauth_agent("root").ok()?;
+    Some(sess)
+}
+
+/// Run a command on the remote machine and return its exit code and output, or None if the
+/// connection failed.
+pub fn remote_status(sess: &Session, command: &str) -> Option<(i32, String)> {
+    let mut channel = sess.channel_session().ok()?;
+    channel.exec(command).ok()?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output).ok()?;
+    channel.wait_close().ok()?;
+    Some((channel.exit_status().ok()?, output))
+}
+
+/// Copy `contents` to `path` on the remote machine.
+pub fn remote_write(sess: &Session, address: &str, path: &str, mode: i32, contents: &[u8]) {
+    let mut channel = sess.scp_send(std::path::Path::new(path), mode, contents.len() as u64, None)
+        .unwrap_or_else(|e| halt!("Could not start copy of {} to {}: {}", path, address, e));
+    channel.write_all(contents).unwrap_or_else(|e| halt!("Could not copy {} to {}: {}", path, address, e));
+    channel.send_eof().unwrap_or_else(|e| halt!("Could not copy {} to {}: {}", path, address, e));
+    channel.wait_eof().unwrap_or_else(|e| halt!("Could not copy {} to {}: {}", path, address, e));
+    channel.close().unwrap_or_else(|e| halt!("Could not copy {} to {}: {}", path, address, e));
+    channel.wait_close().unwrap_or_else(|e| halt!("Could not copy {} to {}: {}", path, address, e));
+}
+
+pub fn remote_output(sess: &Session, ip: &str, command: &str) -> String {
     let mut channel = sess.channel_session().unwrap_or_else(|e| halt!("Could not open SSH channtel to {} : {}", ip, e));
-    channel.exec(crate::disk::DISK_INFO).unwrap_or_else(|e| halt!("SSH command failed: {}", e));
+    channel.exec(command).unwrap_or_else(|e| halt!("SSH command failed: {}", e));
 
     let mut output = String::new();
     channel.read_to_string(&mut output).unwrap_or_else(|e| halt!("Could not read SSH command output: {}", e));
     output
 }
 
+pub fn save_machine(machine: &Machine) {
+    let config = PrettyConfig::new()
+        .struct_names(true)
+        .compact_arrays(false);
+
+    let machine_data = to_string_pretty(machine, config)
+        .unwrap_or_else(|e| halt!("Could not serialize data: {}", e));
+
+    let db = machines_db();
+    db.insert(
+        machine.name.as_bytes(),
+        machine_data.as_bytes()
+    ).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+}
+
 pub fn get_machine(machine_name: &str) -> Option<Machine> {
     let db = machines_db();
     let bytes = db.get(machine_name.as_bytes()).unwrap_or_else(|e| halt!("Could not retreive data for machine {} : {}", machine_name, e))?;
     let string = String::from_utf8_lossy(&bytes);
     ron::from_str(&string).ok()
-}
\ No newline at end of file
+}
+
+/// All machines. Halts if any record cannot be parsed, so callers deciding whether an image is
+/// in use never miss a machine.
+pub fn list() -> Vec<Machine> {
+    machines_db().iter()
+        .map(|item| item.unwrap_or_else(|e| halt!("Error iterating machines tree: {}", e)))
+        .map(|(key, bytes)| ron::from_str(&String::from_utf8_lossy(&bytes)).unwrap_or_else(|e| halt!(
+            "Could not parse the record of machine {}: {}", String::from_utf8_lossy(&key), e
+        )))
+        .collect()
+}
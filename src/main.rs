@@ -22,6 +22,105 @@ enum Command {
         /// Password
         #[arg(short, long)]
         password: String,
+        /// Image to build the machine's root from
+        #[arg(long)]
+        image: Option<String>,
+    },
+    /// Set the users' keys, sudo rules and sshd policy a machine adds to its image, from a YAML file
+    Access {
+        /// Name of the machine
+        machine_name: String,
+        /// Path to the access file
+        file: String,
+    },
+    /// Set the persistent datasets, tmpfs and overlay mounts that make a machine's read-only root writable, from a YAML file
+    State {
+        /// Name of the machine
+        machine_name: String,
+        /// Path to the state file
+        file: String,
+    },
+    /// Render a machine's per-host configuration overlay, to check it before deploying
+    Overlay {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Partition a machine's VM disks and install its image and ZFSBootMenu on them. Wipes the VM's disks
+    Install {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Replicate a machine's tested root from its stopped VM to production
+    Deploy {
+        /// Name of the machine
+        machine_name: String,
+        /// Continue an interrupted deploy
+        #[arg(long, default_value_t = false, conflicts_with_all = ["abort", "plan", "apply"])]
+        resume: bool,
+        /// Discard an interrupted deploy, and the partial stream received in production
+        #[arg(long, default_value_t = false, conflicts_with_all = ["plan", "apply"])]
+        abort: bool,
+        /// Save what the deploy would do to a plan file for review, without touching production
+        #[arg(long, conflicts_with = "apply")]
+        plan: Option<String>,
+        /// Number of deployed boot environments the planned activation keeps
+        #[arg(long, requires = "plan", default_value_t = environment::DEFAULT_KEEP)]
+        keep: usize,
+        /// Carry out a saved plan, unless production changed since it was made
+        #[arg(long)]
+        apply: Option<String>,
+    },
+    /// List what has been deployed to a machine
+    Deployments {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Check whether a machine's production root, state or config files changed since its last deploy. Exits with 2 if they did
+    Drift {
+        /// Name of the machine
+        machine_name: String,
+        /// Output JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Make a deployed snapshot the boot environment production boots by default
+    Activate {
+        /// Name of the machine
+        machine_name: String,
+        /// Deployed snapshot to activate. Defaults to the last one deployed
+        #[arg(long)]
+        snapshot: Option<String>,
+        /// Number of deployed boot environments to keep. Defaults to the number in the plan the snapshot was deployed from, or 3
+        #[arg(long)]
+        keep: Option<usize>,
+        /// Switch into it now with kexec, and only make it the default once it came back healthy
+        #[arg(long, default_value_t = false)]
+        kexec: bool,
+    },
+    /// Make an earlier boot environment production's default again
+    Rollback {
+        /// Name of the machine
+        machine_name: String,
+        /// Boot environment to roll back to. Defaults to the one before the current default
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// List who activated or rolled back which boot environment of a machine, and when
+    Activations {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Manage ZFSBootMenu on a machine's EFI system partitions
+    Zbm {
+        /// Action to perform
+        #[command(subcommand)]
+        action: ZbmAction,
+    },
+    /// Manage the encryption keys of machines' root pools
+    Key {
+        /// Action to perform
+        #[command(subcommand)]
+        action: KeyAction,
     },
     /// Create an image for a specific distribution
     Image {
@@ -43,18 +142,239 @@ enum Command {
     },
     /// List reflectron properties
     Settings,
-    
+    /// Manage multi-machine test topologies
+    Topology {
+        /// Action to perform on the topology
+        #[command(subcommand)]
+        action: TopologyAction,
+    },
 }
 
 #[derive(Parser, Debug)]
 enum ImageAction {
     /// Create a new image
     Create {
-        /// Distribution name
-        distro: String,
-        /// Enable backports (Debian only)
+        /// Distribution name, see 'image distros'
+        #[arg(required_unless_present = "recipe")]
+        distro: Option<String>,
+        /// Build the image from a YAML or RON recipe file instead
+        #[arg(long, conflicts_with_all = ["distro", "backports", "release", "arch", "mirror", "security_mirror", "components", "snapshot", "bootstrapper", "locales", "timezone", "keyboard", "console_font", "hostname"])]
+        recipe: Option<String>,
+        /// Options for building from the command line
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+    /// List the distributions images can be created for
+    Distros,
+    /// List images
+    List,
+    /// Show details of an image
+    Show {
+        /// Name of the image
+        name: String,
+    },
+    /// Delete an image that no machine uses
+    Delete {
+        /// Name of the image
+        name: String,
+    },
+    /// Delete an image and build it again from its recipe or original options
+    Rebuild {
+        /// Name of the image
+        name: String,
+    },
+    /// Run a command inside an image, e.g. 'ref image exec web -- apt-get install -y htop'
+    Exec {
+        /// Name of the image
+        name: String,
+        /// Command and arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Run the hooks of an image's recipe, skipping those already applied
+    RunHooks {
+        /// Name of the image
+        name: String,
+    },
+    /// List the hooks and commands that have been run in an image, with their logs
+    Steps {
+        /// Name of the image
+        name: String,
+    },
+    /// Compare two images or image snapshots (<image>@<snapshot>)
+    Diff {
+        /// Image or snapshot to compare from
+        a: String,
+        /// Image or snapshot to compare to
+        b: String,
+        /// Print the differences as JSON
         #[arg(long, default_value_t = false)]
-        backports: bool,
+        json: bool,
+    },
+    /// Export the software bill of materials of an image as JSON
+    Sbom {
+        /// Name of the image
+        name: String,
+        /// SBOM format, spdx or cyclonedx
+        #[arg(long, default_value = "spdx")]
+        format: String,
+        /// File to write, instead of standard output
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildArgs {
+    /// Enable backports
+    #[arg(long, default_value_t = false)]
+    backports: bool,
+    /// Release codename, defaults to the distribution's current stable or LTS release
+    #[arg(long)]
+    release: Option<String>,
+    /// Target architecture, e.g. amd64 or arm64
+    #[arg(long, default_value = image::DEFAULT_ARCH)]
+    arch: String,
+    /// Package mirror URL, or file:///path for a local repository. Defaults to the
+    /// distribution's mirror setting, then its main archive
+    #[arg(long)]
+    mirror: Option<String>,
+    /// Security archive URL. Not used by default when the mirror is a local repository
+    #[arg(long)]
+    security_mirror: Option<String>,
+    /// Comma separated archive components, defaults to those needed for ZFS
+    #[arg(long, value_delimiter = ',')]
+    components: Option<Vec<String>>,
+    /// Pin packages to an archive snapshot timestamp, e.g. 20240101T000000Z
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// Tool that creates the base system, debootstrap or mmdebstrap
+    #[arg(long)]
+    bootstrapper: Option<String>,
+    /// Comma separated locales to generate, the first is the default. Defaults to en_US.UTF-8
+    #[arg(long, value_delimiter = ',')]
+    locales: Option<Vec<String>>,
+    /// Timezone, e.g. Europe/Berlin. Defaults to Etc/UTC
+    #[arg(long)]
+    timezone: Option<String>,
+    /// XKB keyboard layout, defaults to us
+    #[arg(long)]
+    keyboard: Option<String>,
+    /// console-setup font face, e.g. Terminus. Defaults to Fixed
+    #[arg(long)]
+    console_font: Option<String>,
+    /// Hostname until a machine's overlay sets its own, defaults to the distribution name
+    #[arg(long)]
+    hostname: Option<String>,
+}
+
+impl From<BuildArgs> for image::Choices {
+    fn from(args: BuildArgs) -> image::Choices {
+        image::Choices {
+            release: args.release,
+            arch: Some(args.arch),
+            mirror: args.mirror,
+            security_mirror: args.security_mirror,
+            components: args.components,
+            snapshot: args.snapshot,
+            bootstrapper: args.bootstrapper,
+            locales: args.locales,
+            timezone: args.timezone,
+            keyboard: args.keyboard,
+            console_font: args.console_font,
+            hostname: args.hostname,
+            backports: args.backports,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+enum ZbmAction {
+    /// Install ZFSBootMenu on every ESP of the production machine and add EFI boot entries
+    Install {
+        /// Name of the machine
+        machine_name: String,
+        /// Install on the ESPs of the machine's test VM instead
+        #[arg(long, default_value_t = false)]
+        vm: bool,
+    },
+    /// Check that every ESP of the production machine holds the same ZFSBootMenu image
+    Check {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Set the NIC, address and keys for SSH access to ZFSBootMenu, to unlock encrypted pools, from a YAML file
+    RemoteUnlock {
+        /// Name of the machine
+        machine_name: String,
+        /// Path to the remote unlock file
+        file: String,
+    },
+    /// Build the machine's own ZFSBootMenu with its remote unlock settings, using podman
+    Build {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Check that the machine's ZFSBootMenu build can be reached over SSH in its running test VM
+    Verify {
+        /// Name of the machine
+        machine_name: String,
+        /// Address and port to connect to, instead of the machine's captured address
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Set the kernel command line ZFSBootMenu boots the machine with
+    Commandline {
+        /// Name of the machine
+        machine_name: String,
+        /// Kernel command line, e.g. "quiet loglevel=4"
+        commandline: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum KeyAction {
+    /// Generate a key for a machine, encrypting its pool from the next 'ref install'
+    Create {
+        /// Name of the machine
+        machine_name: String,
+        /// passphrase, hex or raw
+        #[arg(long, default_value = "passphrase")]
+        keyformat: String,
+        /// Where the production machine reads the key when it boots: prompt, or a file:///<path> URL
+        #[arg(long, default_value = "prompt")]
+        keylocation: String,
+    },
+    /// Replace a machine's key, keeping the old one for production until the next deploy
+    Rotate {
+        /// Name of the machine
+        machine_name: String,
+    },
+    /// Show a machine's encryption policy and key file
+    Show {
+        /// Name of the machine
+        machine_name: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum TopologyAction {
+    /// Define or replace a topology from a YAML file
+    Define {
+        /// Path to the topology file
+        file: String,
+    },
+    /// List defined topologies
+    List,
+    /// Bring up the switches and VMs of a topology
+    Up {
+        /// Name of the topology
+        name: String,
+    },
+    /// Stop the VMs of a topology and remove its switches
+    Down {
+        /// Name of the topology
+        name: String,
     },
 }
 
@@ -65,12 +385,47 @@ enum SetAction {
         /// pool name
         name: String,
     },
+    /// Set the default package mirror for Debian images
+    DebianMirror {
+        /// mirror URL
+        url: String,
+    },
+    /// Set the default package mirror for Ubuntu images
+    UbuntuMirror {
+        /// mirror URL
+        url: String,
+    },
+    /// Set a directory of .deb files shared between image builds
+    PackageCache {
+        /// absolute path of the cache directory
+        dir: String,
+    },
+    /// Set the directory holding reflectron's config tree, overriding the built in one
+    DataDir {
+        /// absolute path of the data directory
+        dir: String,
+    },
+    /// Set a local ZFSBootMenu EFI image to install instead of downloading the release
+    ZbmEfi {
+        /// absolute path of the EFI image
+        path: String,
+    },
 }
 
 #[derive(Parser, Debug)]
 enum GetAction {
     /// Set the ZPool to use for disk images
     DiskPool,
+    /// Get the default package mirror for Debian images
+    DebianMirror,
+    /// Get the default package mirror for Ubuntu images
+    UbuntuMirror,
+    /// Get the package cache directory
+    PackageCache,
+    /// Get the directory holding reflectron's config tree
+    DataDir,
+    /// Get the local ZFSBootMenu EFI image
+    ZbmEfi,
 }
 
 
@@ -78,13 +433,126 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::New { machine_name, ip, password } => {
-            machine::new(&machine_name, &ip, &password);
+        Command::New { machine_name, ip, password, image } => {
+            machine::new(&machine_name, &ip, &password, image.as_deref());
+        }
+        Command::Access { machine_name, file } => {
+            access::set(&machine_name, &file);
+        }
+        Command::State { machine_name, file } => {
+            state::set(&machine_name, &file);
+        }
+        Command::Overlay { machine_name } => {
+            let machine = machine::get_machine(&machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+            println!("{}", overlay::render(&machine));
+        }
+        Command::Install { machine_name } => {
+            let machine = machine::get_machine(&machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+            install::install(&machine);
+        }
+        Command::Deploy { machine_name, resume, abort, plan, keep, apply } => {
+            match (plan, apply) {
+                (Some(file), _) => deploy::plan(&machine_name, &file, keep),
+                (_, Some(file)) => deploy::apply(&machine_name, &file),
+                _ => deploy::deploy(&machine_name, resume, abort),
+            }
+        }
+        Command::Deployments { machine_name } => {
+            deploy::print_deployments(&machine_name);
+        }
+        Command::Drift { machine_name, json } => {
+            drift::drift(&machine_name, json);
+        }
+        Command::Activate { machine_name, snapshot, keep, kexec } => {
+            environment::activate(&machine_name, snapshot.as_deref(), keep, kexec);
+        }
+        Command::Rollback { machine_name, to } => {
+            environment::rollback(&machine_name, to.as_deref());
+        }
+        Command::Activations { machine_name } => {
+            environment::print_activations(&machine_name);
+        }
+        Command::Zbm { action } => {
+            match action {
+                ZbmAction::Install { machine_name, vm } => {
+                    let machine = machine::get_machine(&machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+                    if vm {
+                        install::update_vm_boot(&machine);
+                    } else {
+                        zbm::install_remote(&machine);
+                    }
+                }
+                ZbmAction::Check { machine_name } => {
+                    let machine = machine::get_machine(&machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+                    zbm::check_remote(&machine);
+                }
+                ZbmAction::RemoteUnlock { machine_name, file } => {
+                    zbm::set_remote_unlock(&machine_name, &file);
+                }
+                ZbmAction::Build { machine_name } => {
+                    zbm::build(&machine_name);
+                }
+                ZbmAction::Verify { machine_name, address } => {
+                    zbm::verify(&machine_name, address.as_deref());
+                }
+                ZbmAction::Commandline { machine_name, commandline } => {
+                    zbm::set_commandline(&machine_name, &commandline);
+                }
+            }
+        }
+        Command::Key { action } => {
+            match action {
+                KeyAction::Create { machine_name, keyformat, keylocation } => {
+                    keys::create(&machine_name, &keyformat, &keylocation);
+                }
+                KeyAction::Rotate { machine_name } => {
+                    keys::rotate(&machine_name);
+                }
+                KeyAction::Show { machine_name } => {
+                    keys::show(&machine_name);
+                }
+            }
         }
         Command::Image { action } => {
             match action {
-                ImageAction::Create { distro, backports } => {
-                    create_image(&distro, backports);
+                ImageAction::Create { distro, recipe, build } => {
+                    match (recipe, distro) {
+                        (Some(recipe), _) => image::recipe::create(&recipe),
+                        (None, Some(distro)) => create_image(&distro, (*build).into()),
+                        (None, None) => unreachable!("clap requires a distro or a recipe"),
+                    }
+                }
+                ImageAction::Distros => {
+                    for builder in image::BUILDERS {
+                        println!("{}: {} (default {})", builder.distro(), builder.releases().join(", "), builder.default_release());
+                    }
+                }
+                ImageAction::List => {
+                    image::list();
+                }
+                ImageAction::Show { name } => {
+                    image::show(&name);
+                }
+                ImageAction::Delete { name } => {
+                    image::delete(&name);
+                }
+                ImageAction::Rebuild { name } => {
+                    image::rebuild(&name);
+                }
+                ImageAction::Exec { name, command } => {
+                    image::exec::exec(&name, &command);
+                }
+                ImageAction::RunHooks { name } => {
+                    image::exec::run_hooks(&name);
+                }
+                ImageAction::Steps { name } => {
+                    image::exec::print_steps(&name);
+                }
+                ImageAction::Diff { a, b, json } => {
+                    image::diff::run(&a, &b, json);
+                }
+                ImageAction::Sbom { name, format, output } => {
+                    image::manifest::export(&name, &format, output.as_deref());
                 }
             }
         }
@@ -93,6 +561,21 @@ fn main() {
                 SetAction::DiskPool { name } => {
                     settings::set(Key::DiskPool, &name);
                 }
+                SetAction::DebianMirror { url } => {
+                    settings::set(Key::DebianMirror, &url);
+                }
+                SetAction::UbuntuMirror { url } => {
+                    settings::set(Key::UbuntuMirror, &url);
+                }
+                SetAction::PackageCache { dir } => {
+                    settings::set(Key::PackageCache, &dir);
+                }
+                SetAction::DataDir { dir } => {
+                    settings::set(Key::DataDir, &dir);
+                }
+                SetAction::ZbmEfi { path } => {
+                    settings::set(Key::ZbmEfi, &path);
+                }
             }
         }
         Command::Get { action } => {
@@ -100,6 +583,21 @@ fn main() {
                 GetAction::DiskPool => {
                     println!("{}", settings::get(Key::DiskPool).unwrap_or("Not set".to_owned()));
                 }
+                GetAction::DebianMirror => {
+                    println!("{}", settings::get(Key::DebianMirror).unwrap_or("Not set".to_owned()));
+                }
+                GetAction::UbuntuMirror => {
+                    println!("{}", settings::get(Key::UbuntuMirror).unwrap_or("Not set".to_owned()));
+                }
+                GetAction::PackageCache => {
+                    println!("{}", settings::get(Key::PackageCache).unwrap_or("Not set".to_owned()));
+                }
+                GetAction::DataDir => {
+                    println!("{}", data::data_dir().unwrap_or("Built in".to_owned()));
+                }
+                GetAction::ZbmEfi => {
+                    println!("{}", settings::get(Key::ZbmEfi).unwrap_or("Release download".to_owned()));
+                }
             }
         }
         Command::Settings => {
@@ -113,21 +611,41 @@ fn main() {
                 }
             }
         }
+        Command::Topology { action } => {
+            match action {
+                TopologyAction::Define { file } => {
+                    topology::define(&file);
+                }
+                TopologyAction::List => {
+                    for name in topology::list() {
+                        println!("{}", name);
+                    }
+                }
+                TopologyAction::Up { name } => {
+                    topology::up(&name);
+                }
+                TopologyAction::Down { name } => {
+                    topology::down(&name);
+                }
+            }
+        }
     }
 }
 
 
 
 
-fn create_image(distro: &str, backports: bool) {
-    println!("Creating image for distribution: {}", distro);
-    match distro.to_lowercase().as_str() {
-        "debian" => {
-            if backports {
-                println!("Backports enabled");
-            }
-            image::debian::create(backports)
-        }
-        _ => println!("Unsupported distribution: {}\nOnly Debian 12 is supported at the current time.", distro),
+fn create_image(distro: &str, choices: image::Choices) {
+    let builder = image::builder(distro).unwrap_or_else(|| halt!(
+        "Unsupported distribution: {}\nSupported distributions are: {}",
+        distro,
+        image::supported_distros()
+    ));
+    println!("Creating image for distribution: {}", builder.distro());
+    if choices.backports {
+        println!("Backports enabled");
     }
-}
\ No newline at end of file
+
+    let options = image::Options::new(builder, choices);
+    image::build(builder, &options);
+}
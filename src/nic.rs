@@ -0,0 +1,131 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+pub const NIC_INFO: &str = "
+        for nic in $(ls /sys/class/net | grep -v ^lo$); do
+            echo \"Nic: $nic\";
+            echo \"MAC: $(cat /sys/class/net/$nic/address)\";
+            ip -o addr show dev $nic | awk '{print \"Address: \" $4}';
+            echo '';
+        done;
+        ip route show default | awk '{print \"Gateway: \" $5 \" \" $3}'
+    ";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Nic {
+    pub name: String,
+    pub mac: String,
+    pub addresses: Vec<String>,
+    pub gateway: Option<String>,
+}
+
+impl fmt::Display for Nic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name: {}", self.name)?;
+        writeln!(f, "MAC: {}", self.mac)?;
+        for address in &self.addresses {
+            writeln!(f, "Address: {}", address)?;
+        }
+        if let Some(gateway) = &self.gateway {
+            writeln!(f, "Gateway: {}", gateway)?;
+        }
+        Ok(())
+    }
+}
+
+
+pub fn parse_output(output: &str) -> Vec<Nic> {
+    let mut nics: Vec<Nic> = Vec::new();
+
+    for line in output.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix("Nic: ") {
+            nics.push(Nic {
+                name: name.to_string(),
+                mac: String::new(),
+                addresses: Vec::new(),
+                gateway: None,
+            });
+            continue;
+        }
+
+        if let Some(mac) = line.strip_prefix("MAC: ") {
+            if let Some(nic) = nics.last_mut() {
+                nic.mac = mac.to_string();
+            }
+            continue;
+        }
+
+        if let Some(address) = line.strip_prefix("Address: ") {
+            if let Some(nic) = nics.last_mut() {
+                nic.addresses.push(address.to_string());
+            }
+            continue;
+        }
+
+        // default routes are listed after all interfaces as "Gateway: <dev> <ip>"
+        if let Some(route) = line.strip_prefix("Gateway: ") {
+            if let Some((dev, gateway)) = route.split_once(' ') {
+                if let Some(nic) = nics.iter_mut().find(|n| n.name == dev) {
+                    nic.gateway = Some(gateway.to_string());
+                }
+            }
+        }
+    }
+
+    nics
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nics_addresses_and_gateways() {
+        let output = "
+            Nic: eth0
+            MAC: 52:54:00:12:34:56
+            Address: 192.168.1.10/24
+            Address: fe80::5054:ff:fe12:3456/64
+
+            Nic: eth1
+            MAC: 52:54:00:ab:cd:ef
+
+            Nic: eth2
+            MAC: 52:54:00:00:00:02
+            Address: 10.0.0.5/8
+
+            Gateway: eth0 192.168.1.1
+            Gateway: eth2 10.0.0.1
+        ";
+        let nics = parse_output(output);
+        let summary: Vec<(&str, &str, Vec<&str>, Option<&str>)> = nics.iter()
+            .map(|n| (n.name.as_str(), n.mac.as_str(), n.addresses.iter().map(|a| a.as_str()).collect(), n.gateway.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("eth0", "52:54:00:12:34:56", vec!["192.168.1.10/24", "fe80::5054:ff:fe12:3456/64"], Some("192.168.1.1")),
+            ("eth1", "52:54:00:ab:cd:ef", vec![], None),
+            ("eth2", "52:54:00:00:00:02", vec!["10.0.0.5/8"], Some("10.0.0.1")),
+        ]);
+    }
+
+    #[test]
+    fn ignores_stray_lines() {
+        let cases = [
+            ("", 0),
+            ("MAC: 52:54:00:12:34:56\nAddress: 10.0.0.1/8\n", 0),
+            ("Gateway: eth0 10.0.0.1\n", 0),
+            ("Nic: eth0\nGateway: eth9 10.0.0.1\nGateway: eth0\n", 1),
+        ];
+        for (output, count) in cases {
+            let nics = parse_output(output);
+            assert_eq!(nics.len(), count, "{:?}", output);
+            assert!(nics.iter().all(|n| n.mac.is_empty() && n.addresses.is_empty() && n.gateway.is_none()), "{:?}", output);
+        }
+    }
+}
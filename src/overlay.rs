@@ -0,0 +1,186 @@
+use std::fs;
+use std::path::Path;
+use std::os::unix::fs::PermissionsExt;
+use std::collections::BTreeMap;
+use crate::*;
+use crate::image::manifest::sha256_hex;
+use crate::image::recipe::Recipe;
+use crate::machine::Machine;
+
+pub const MACHINES_DIR: &str = "/opt/reflectron/machines";
+
+/// Pool the production root is installed on
+pub const ROOT_POOL: &str = "rpool";
+
+
+/// Rendered per-machine files, laid out as they will appear under the machine's root.
+pub fn overlay_path(machine_name: &str) -> String {
+    format!("{}/{}/overlay", MACHINES_DIR, machine_name)
+}
+
+
+/// The zfs hostid for a machine, stable across rebuilds so pools import without `-f`.
+pub fn hostid(machine_name: &str) -> String {
+    sha256_hex(machine_name.as_bytes())[..8].to_owned()
+}
+
+
+/// Facts about a machine that overlay templates can reference, along with the variables of
+/// the machine's image. NICs and disks are addressed by name, e.g. `{{ nic.eth0.address }}`.
+pub fn machine_vars(machine: &Machine) -> BTreeMap<String, String> {
+    let mut vars = match machine.image.as_deref().and_then(image::get_image) {
+        Some(image) => image::recipe::template_vars(&image.options),
+        None => BTreeMap::new(),
+    };
+
+    vars.insert("machine".to_owned(), machine.name.clone());
+    vars.insert("hostname".to_owned(), machine.name.clone());
+    vars.insert("hostid".to_owned(), hostid(&machine.name));
+    let pool = machine.pool.clone().unwrap_or_else(|| install::planned_pool(machine));
+    vars.insert("pool".to_owned(), pool.name);
+    vars.insert("pool_layout".to_owned(), pool.layout);
+    vars.insert(
+        "management_address".to_owned(),
+        machine.address.as_deref().map(|a| a.rsplit_once(':').map(|(host, _)| host).unwrap_or(a)).unwrap_or("").to_owned(),
+    );
+
+    vars.insert("nics".to_owned(), machine.nics.iter().map(|n| n.name.as_str()).collect::<Vec<_>>().join(" "));
+    vars.insert("gateway".to_owned(), machine.nics.iter().find_map(|n| n.gateway.clone()).unwrap_or_default());
+    for nic in &machine.nics {
+        let prefix = format!("nic.{}", nic.name);
+        let address = nic.addresses.first().cloned().unwrap_or_default();
+        vars.insert(format!("{}.mac", prefix), nic.mac.clone());
+        vars.insert(format!("{}.ip", prefix), address.split('/').next().unwrap_or("").to_owned());
+        vars.insert(format!("{}.address", prefix), address);
+        vars.insert(format!("{}.addresses", prefix), nic.addresses.join(" "));
+        vars.insert(format!("{}.gateway", prefix), nic.gateway.clone().unwrap_or_default());
+    }
+
+    vars.insert("disks".to_owned(), machine.disks.iter().map(|d| d.name.as_str()).collect::<Vec<_>>().join(" "));
+    vars.insert("disk_count".to_owned(), machine.disks.len().to_string());
+    for disk in &machine.disks {
+        let prefix = format!("disk.{}", disk.name);
+        vars.insert(format!("{}.size", prefix), disk.size.clone());
+        vars.insert(format!("{}.wwn", prefix), disk.wwn.clone().unwrap_or_default());
+        vars.insert(format!("{}.serial", prefix), disk.serial.clone().unwrap_or_default());
+    }
+
+    vars
+}
+
+
+/// Write a file into the overlay with mode 0644, which callers change where it needs another.
+pub fn write(overlay: &str, dest: &str, contents: &[u8]) {
+    if !dest.starts_with('/') || dest.split('/').any(|part| part == "..") {
+        halt!("Invalid overlay destination {}. Destinations must be absolute paths inside the machine's root.", dest);
+    }
+    let path = format!("{}{}", overlay, dest);
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent).unwrap_or_else(|e| halt!("Could not create overlay directory {:?}: {}", parent, e));
+    }
+    fs::write(&path, contents).unwrap_or_else(|e| halt!("Could not write overlay file {}: {}", path, e));
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap_or_else(|e| halt!("Could not set mode of {}: {}", path, e));
+}
+
+
+/// The overlay is copied onto the root with its modes, so give its directories the modes of
+/// the image's directories they land on, or 0755 for new ones, rather than whatever the umask
+/// left them with.
+fn set_directory_modes(machine: &Machine, overlay: &str) {
+    let image_root = machine.image.as_deref().map(image::image_path);
+    let mut pending = vec![overlay.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let mode = image_root.as_ref()
+            .and_then(|root| fs::metadata(format!("{}{}", root, &dir[overlay.len()..])).ok())
+            .filter(|m| m.is_dir())
+            .map(|m| m.permissions().mode() & 0o7777)
+            .unwrap_or(0o755);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(mode)).unwrap_or_else(|e| halt!("Could not set mode of {}: {}", dir, e));
+        for entry in fs::read_dir(&dir).unwrap_or_else(|e| halt!("Could not read overlay directory {}: {}", dir, e)).flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(entry.path().to_string_lossy().into_owned());
+            }
+        }
+    }
+}
+
+
+/// Machine keys are added to those the image's recipe installs for the same user, since the
+/// overlay file replaces the image's. Sudo rules and the sshd policy get their own files.
+fn render_access(machine: &Machine, overlay: &str, recipe: Option<&Recipe>) {
+    let accounts: Option<Vec<String>> = machine.image.as_deref()
+        .and_then(image::manifest::get_manifest)
+        .map(|manifest| manifest.accounts.into_iter().map(|a| a.name).collect());
+
+    for login in &machine.access.users {
+        if let Some(accounts) = &accounts {
+            if !accounts.contains(&login.name) {
+                halt!("Machine {} grants access to user {}, who does not exist in image {}", machine.name, login.name, machine.image.as_deref().unwrap_or("-"));
+            }
+        }
+        if !login.authorized_keys.is_empty() {
+            let mut keys: Vec<String> = recipe
+                .and_then(|r| r.users.iter().find(|u| u.name == login.name))
+                .map(|u| u.authorized_keys.clone())
+                .unwrap_or_default();
+            keys.extend(login.authorized_keys.iter().cloned());
+            write(overlay, &access::authorized_keys_path(&login.name), format!("{}\n", keys.join("\n")).as_bytes());
+        }
+        if let Some(rule) = &login.sudo {
+            let path = access::sudoers_path("machine", &login.name);
+            write(overlay, &path, access::sudoers(&login.name, rule).as_bytes());
+            let file = format!("{}{}", overlay, path);
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o440)).unwrap_or_else(|e| halt!("Could not set mode of {}: {}", file, e));
+        }
+    }
+    if let Some(ssh) = &machine.access.ssh {
+        write(overlay, access::SSHD_CONFIG_PATH, ssh.sshd_config().as_bytes());
+    }
+}
+
+
+/// Render the machine's overlay from scratch and return its path. Every machine gets its
+/// hostname, hosts file, hostid and access files; the `overlay` files of its image's recipe are rendered
+/// as templates on top.
+pub fn render(machine: &Machine) -> String {
+    let overlay = overlay_path(&machine.name);
+    if Path::new(&overlay).exists() {
+        fs::remove_dir_all(&overlay).unwrap_or_else(|e| halt!("Could not clear overlay {}: {}", overlay, e));
+    }
+    let vars = machine_vars(machine);
+
+    write(&overlay, "/etc/hostname", format!("{}\n", machine.name).as_bytes());
+    write(&overlay, "/etc/hosts", format!(
+        "127.0.0.1\tlocalhost\n127.0.1.1\t{0}\n\n::1\tlocalhost ip6-localhost ip6-loopback\nff02::1\tip6-allnodes\nff02::2\tip6-allrouters\n",
+        machine.name
+    ).as_bytes());
+    // /etc/hostid holds the id as a native endian 32 bit integer, as written by zgenhostid
+    let id = u32::from_str_radix(&vars["hostid"], 16).unwrap_or_else(|e| halt!("Invalid hostid: {}", e));
+    write(&overlay, "/etc/hostid", &id.to_ne_bytes());
+
+    let recipe_path = machine.image.as_deref()
+        .and_then(image::get_image)
+        .and_then(|image| image.recipe);
+    let recipe = recipe_path.as_deref().map(Recipe::load);
+    render_access(machine, &overlay, recipe.as_ref());
+    state::render(machine, &overlay);
+    environment::render_watchdog(&overlay);
+
+    if let (Some(recipe_path), Some(recipe)) = (recipe_path, recipe) {
+        let recipe_dir = Path::new(&recipe_path).parent().unwrap_or(Path::new(".")).to_path_buf();
+        for file in &recipe.overlay {
+            let source = image::recipe::resolve(&recipe_dir, &file.source);
+            let template = fs::read_to_string(&source).unwrap_or_else(|e| halt!("Could not read overlay template {:?}: {}", source, e));
+            let contents = template::render(&source.to_string_lossy(), &template, &vars);
+            write(&overlay, &file.dest, contents.as_bytes());
+            let path = format!("{}{}", overlay, file.dest);
+            let mode = u32::from_str_radix(&file.mode, 8).unwrap_or_else(|_| halt!("Invalid mode {} for overlay file {}", file.mode, file.dest));
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap_or_else(|e| halt!("Could not set mode of {}: {}", path, e));
+        }
+    }
+
+    set_directory_modes(machine, &overlay);
+
+    log!("Rendered overlay for machine {} in {}", machine.name, overlay);
+    overlay
+}
@@ -6,6 +6,11 @@ use strum_macros::{AsRefStr, Display};
 #[strum(serialize_all = "snake_case")]
 pub enum Key {
     DiskPool,
+    DebianMirror,
+    UbuntuMirror,
+    PackageCache,
+    DataDir,
+    ZbmEfi,
 }
 
 fn settings_db() -> sled::Tree {
@@ -45,4 +50,9 @@ pub fn list() -> Vec<(String, String)> {
     }
     
     result
-}
\ No newline at end of file
+}
+
+
+pub fn disk_pool() -> String {
+    get(Key::DiskPool).unwrap_or_else(|| halt!("Reflectron property disk-pool has not been set. Use 'ref set disk-pool <poolname>' to set it, and retry this command."))
+}
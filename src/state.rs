@@ -0,0 +1,400 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use serde::{Serialize, Deserialize};
+use ssh2::Session;
+use crate::*;
+use crate::machine::Machine;
+use crate::overlay::ROOT_POOL;
+
+/// Parent of a machine's state datasets, beside the root that deploys receive into, so
+/// replication never touches them.
+pub const STATE_CONTAINER: &str = "reflectron/state";
+/// State dataset holding the upper and work directories of overlay mounts.
+const OVERLAY_STORE_DATASET: &str = "overlays";
+/// Where the overlay store is mounted.
+pub const OVERLAY_STORE: &str = "/reflectron";
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+const KEY_UNIT: &str = "reflectron-state-key.service";
+const OVERLAY_DIRS_UNIT: &str = "reflectron-overlay-dirs.service";
+
+
+/// Writable places on top of a machine's read-only root.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct State {
+    #[serde(default)]
+    pub datasets: Vec<Dataset>,
+    #[serde(default)]
+    pub tmpfs: Vec<Tmpfs>,
+    #[serde(default)]
+    pub overlays: Vec<Overlay>,
+    /// Files whose checksums 'ref drift' compares with the deployed root, besides the
+    /// machine's overlay files
+    #[serde(default)]
+    pub checked_files: Vec<String>,
+}
+
+/// A persistent dataset, `rpool/reflectron/state/<name>`, mounted at `path`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dataset {
+    pub name: String,
+    pub path: String,
+}
+
+/// A tmpfs mounted at `path`, emptied on every boot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tmpfs {
+    pub path: String,
+    /// e.g. "512M" or "10%"
+    #[serde(default)]
+    pub size: Option<String>,
+    #[serde(default = "default_tmpfs_mode")]
+    pub mode: String,
+}
+
+fn default_tmpfs_mode() -> String {
+    "0755".to_owned()
+}
+
+/// An overlayfs over `path` of the image, whose changes persist in the overlay store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Overlay {
+    pub path: String,
+    /// Changes are expected, e.g. logs, so 'ref drift' does not report them
+    #[serde(default)]
+    pub ignore_drift: bool,
+}
+
+
+impl State {
+    fn is_empty(&self) -> bool {
+        self.datasets.is_empty() && self.tmpfs.is_empty() && self.overlays.is_empty()
+    }
+
+    /// Every mount path, in the order they are declared.
+    fn paths(&self) -> Vec<&str> {
+        self.datasets.iter().map(|d| d.path.as_str())
+            .chain(self.tmpfs.iter().map(|t| t.path.as_str()))
+            .chain(self.overlays.iter().map(|o| o.path.as_str()))
+            .collect()
+    }
+
+    /// Names of the state datasets to create, the overlay store included.
+    fn dataset_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.datasets.iter().map(|d| d.name.as_str()).collect();
+        if !self.overlays.is_empty() {
+            names.push(OVERLAY_STORE_DATASET);
+        }
+        names
+    }
+
+    fn check(&self) {
+        let mut paths: Vec<&str> = Vec::new();
+        for path in self.paths() {
+            let valid = path.starts_with('/') && path.len() > 1 && !path.ends_with('/')
+                && !path.contains("//") && !path.split('/').any(|part| part == "." || part == "..")
+                && path.chars().all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c));
+            if !valid {
+                halt!("Invalid state path {}. Paths must be absolute, below / and made of letters, digits, '.', '_' and '-'.", path);
+            }
+            if path == OVERLAY_STORE || path.starts_with(&format!("{}/", OVERLAY_STORE)) {
+                halt!("State path {} is reserved for the overlay store", path);
+            }
+            if paths.contains(&path) {
+                halt!("State path {} is declared twice", path);
+            }
+            paths.push(path);
+        }
+        for overlay in &self.overlays {
+            if let Some(other) = self.overlays.iter().find(|o| overlay.path.starts_with(&format!("{}/", o.path))) {
+                halt!("Overlay {} is inside overlay {}", overlay.path, other.path);
+            }
+        }
+
+        for file in &self.checked_files {
+            if !file.starts_with('/') || file.split('/').any(|part| part == "..") || !file.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+@".contains(c)) {
+                halt!("Invalid checked file {}", file);
+            }
+        }
+
+        let mut names: Vec<&str> = Vec::new();
+        for dataset in &self.datasets {
+            if dataset.name.is_empty() || !dataset.name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+                halt!("Invalid state dataset name {}", dataset.name);
+            }
+            if dataset.name == OVERLAY_STORE_DATASET {
+                halt!("State dataset name {} is reserved for the overlay store", dataset.name);
+            }
+            if names.contains(&dataset.name.as_str()) {
+                halt!("State dataset {} is declared twice", dataset.name);
+            }
+            names.push(&dataset.name);
+        }
+
+        for tmpfs in &self.tmpfs {
+            if !(3..=4).contains(&tmpfs.mode.len()) || u32::from_str_radix(&tmpfs.mode, 8).is_err() {
+                halt!("Invalid mode {} for tmpfs {}", tmpfs.mode, tmpfs.path);
+            }
+            if let Some(size) = &tmpfs.size {
+                let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+                if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) || size.len() - digits.len() > 1 {
+                    halt!("Invalid size {} for tmpfs {}", size, tmpfs.path);
+                }
+            }
+        }
+    }
+}
+
+
+/// Load a machine's state file and store it with the machine, replacing any earlier one.
+pub fn set(machine_name: &str, file: &str) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| halt!("Could not read state file {}: {}", file, e));
+    let state: State = serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse state file {}: {}", file, e));
+    state.check();
+    machine.state = state;
+    machine::save_machine(&machine);
+    log!(
+        "Set state for machine {}: {} dataset(s), {} tmpfs, {} overlay(s). New datasets are created on the next 'ref install' or 'ref activate'.",
+        machine_name, machine.state.datasets.len(), machine.state.tmpfs.len(), machine.state.overlays.len()
+    );
+}
+
+
+/// The parent of the state datasets in `pool`, which is also their encryption root.
+fn container(pool: &str) -> String {
+    format!("{}/{}", pool, STATE_CONTAINER)
+}
+
+/// The state encryption root of the test VM's pool imported on the host, if it has one.
+pub fn host_encryption_root(machine: &Machine) -> Option<String> {
+    machine.encryption.as_ref()?;
+    let root = container(&install::host_pool(&machine.name));
+    let mut command = zfs_query(&["list", &root]);
+    command.stderr(std::process::Stdio::null());
+    success_stauts(command).then_some(root)
+}
+
+
+/// Create the state datasets in the test VM's pool, encrypted with the machine's key like its
+/// boot environments. The pool must be imported.
+pub fn create_host(machine: &Machine) {
+    let names = machine.state.dataset_names();
+    if names.is_empty() {
+        return;
+    }
+    let pool = install::host_pool(&machine.name);
+    perform(
+        "Create reflectron dataset",
+        None,
+        zfs(&["create", "-o", "canmount=off", "-o", "mountpoint=none", &format!("{}/reflectron", pool)]),
+        false
+    );
+    let root = container(&pool);
+    let encryption = keys::create_options(machine);
+    let mut args = vec!["create", "-o", "canmount=off", "-o", "mountpoint=none"];
+    args.extend(encryption.iter().map(|o| o.as_str()));
+    args.push(&root);
+    perform("Create state dataset container", None, zfs(&args), false);
+
+    for name in names {
+        let dataset = format!("{}/{}", root, name);
+        perform(&format!("Create state dataset {}", dataset), None, zfs(&["create", "-o", "mountpoint=legacy", &dataset]), false);
+    }
+    keys::set_boot_keylocation(machine, &root);
+}
+
+
+/// The state datasets production needs for the machine, their container first.
+pub fn remote_datasets(machine: &Machine) -> Vec<String> {
+    let names = machine.state.dataset_names();
+    if names.is_empty() {
+        return Vec::new();
+    }
+    let root = container(ROOT_POOL);
+    let mut datasets = vec![root.clone()];
+    datasets.extend(names.iter().map(|name| format!("{}/{}", root, name)));
+    datasets
+}
+
+
+/// Create the state datasets production is missing. Existing ones are left as they are, with
+/// their data.
+pub fn create_remote(machine: &Machine, sess: &Session, address: &str) {
+    let names = machine.state.dataset_names();
+    if names.is_empty() {
+        return;
+    }
+    let root = container(ROOT_POOL);
+    let (status, _) = machine::remote_status(sess, &format!("zfs list {}", root))
+        .unwrap_or_else(|| halt!("Could not check state datasets on {}", machine.name));
+    if status != 0 {
+        let encryption = match &machine.encryption {
+            Some(encryption) if encryption.keylocation == "prompt" => halt!(
+                "{} has no {} and its key can only be typed at a prompt. Create it on {} with 'zfs create -o canmount=off -o mountpoint=none -o encryption=aes-256-gcm -o keyformat=passphrase -o keylocation=prompt {}'.",
+                machine.name, root, machine.name, root
+            ),
+            Some(encryption) => format!("-o encryption=aes-256-gcm -o keyformat={} -o keylocation={} ", encryption.keyformat, encryption.keylocation),
+            None => String::new(),
+        };
+        machine::remote_run(sess, address, "Create state dataset container", &format!(
+            "zfs create -p -o canmount=off -o mountpoint=none {}{}", encryption, root
+        ));
+    }
+    for name in names {
+        machine::remote_run(sess, address, &format!("Create state dataset {}", name), &format!(
+            "zfs list {0}/{1} >/dev/null 2>&1 || zfs create -o mountpoint=legacy {0}/{1}", root, name
+        ));
+    }
+}
+
+
+/// The systemd unit name of a mount at `path`, as systemd-escape --path makes it.
+fn mount_unit(path: &str) -> String {
+    let escaped: String = path.trim_start_matches('/').chars().enumerate()
+        .map(|(i, c)| match c {
+            '/' => "-".to_owned(),
+            '-' => "\\x2d".to_owned(),
+            '.' if i == 0 => "\\x2e".to_owned(),
+            c => c.to_string(),
+        })
+        .collect();
+    format!("{}.mount", escaped)
+}
+
+fn unit(description: &str, dependencies: &[String], section: &str, settings: &[String]) -> String {
+    let mut unit = format!("# Managed by reflectron\n[Unit]\nDescription={}\nDefaultDependencies=no\n", description);
+    for dependency in dependencies {
+        unit.push_str(dependency);
+        unit.push('\n');
+    }
+    unit.push_str("Conflicts=umount.target\nBefore=local-fs.target umount.target\n\n");
+    unit.push_str(&format!("[{}]\n", section));
+    for setting in settings {
+        unit.push_str(setting);
+        unit.push('\n');
+    }
+    unit
+}
+
+/// The command loading the state encryption root's key at boot, if it is encrypted.
+fn load_key_command(machine: &Machine) -> Option<String> {
+    let root = container(ROOT_POOL);
+    machine.encryption.as_ref().map(|encryption| {
+        let load = if encryption.keylocation == "prompt" {
+            format!("systemd-ask-password \"Passphrase for {0}:\" | zfs load-key {0}", root)
+        } else {
+            format!("zfs load-key {}", root)
+        };
+        format!("ExecStart=/bin/sh -c '[ \"$(zfs get -H -o value keystatus {})\" = available ] || {}'", root, load)
+    })
+}
+
+
+/// Write the machine's state mounts into its overlay as systemd units, enabled for
+/// local-fs.target, along with their mountpoints, since the root they are copied into is
+/// read-only once deployed. Oneshot services load the state key before the datasets are
+/// mounted, and create the overlays' upper and work directories in the store before the
+/// overlays are.
+pub fn render(machine: &Machine, overlay: &str) {
+    let state = &machine.state;
+    if state.is_empty() {
+        return;
+    }
+    let units = format!("{}{}", overlay, UNIT_DIR);
+    let wants = format!("{}/local-fs.target.wants", units);
+    fs::create_dir_all(&wants).unwrap_or_else(|e| halt!("Could not create overlay directory {}: {}", wants, e));
+    let write_unit = |name: &str, contents: &str, enable: bool| {
+        overlay::write(overlay, &format!("{}/{}", UNIT_DIR, name), contents.as_bytes());
+        if enable {
+            let link = format!("{}/{}", wants, name);
+            symlink(format!("../{}", name), &link).unwrap_or_else(|e| halt!("Could not enable unit {}: {}", link, e));
+        }
+    };
+    let mountpoint = |path: &str| {
+        let dir = format!("{}{}", overlay, path);
+        fs::create_dir_all(&dir).unwrap_or_else(|e| halt!("Could not create overlay directory {}: {}", dir, e));
+    };
+
+    let root = container(ROOT_POOL);
+    let mut dataset_dependencies = vec!["After=zfs-import.target".to_owned()];
+    if let Some(load_key) = load_key_command(machine) {
+        write_unit(KEY_UNIT, &unit(
+            &format!("Load key of {}", root),
+            &["After=zfs-import.target".to_owned()],
+            "Service",
+            &["Type=oneshot".to_owned(), "RemainAfterExit=yes".to_owned(), load_key],
+        ), false);
+        dataset_dependencies.push(format!("Requires={0}\nAfter={0}", KEY_UNIT));
+    }
+
+    let mut datasets: Vec<(String, &str)> = state.datasets.iter().map(|d| (format!("{}/{}", root, d.name), d.path.as_str())).collect();
+    if !state.overlays.is_empty() {
+        datasets.push((format!("{}/{}", root, OVERLAY_STORE_DATASET), OVERLAY_STORE));
+    }
+    for (dataset, path) in &datasets {
+        mountpoint(path);
+        write_unit(&mount_unit(path), &unit(
+            &format!("State dataset {}", dataset),
+            &dataset_dependencies,
+            "Mount",
+            &[format!("What={}", dataset), format!("Where={}", path), "Type=zfs".to_owned()],
+        ), true);
+    }
+
+    for tmpfs in &state.tmpfs {
+        mountpoint(&tmpfs.path);
+        let mut options = format!("mode={},nosuid,nodev", tmpfs.mode);
+        if let Some(size) = &tmpfs.size {
+            options.push_str(&format!(",size={}", size));
+        }
+        write_unit(&mount_unit(&tmpfs.path), &unit(
+            &format!("Temporary {}", tmpfs.path),
+            &[],
+            "Mount",
+            &["What=tmpfs".to_owned(), format!("Where={}", tmpfs.path), "Type=tmpfs".to_owned(), format!("Options={}", options)],
+        ), true);
+    }
+
+    if state.overlays.is_empty() {
+        return;
+    }
+    let dirs: Vec<String> = state.overlays.iter()
+        .flat_map(|o| [format!("{}{}/upper", OVERLAY_STORE, o.path), format!("{}{}/work", OVERLAY_STORE, o.path)])
+        .collect();
+    write_unit(OVERLAY_DIRS_UNIT, &unit(
+        "Create overlay directories",
+        &[format!("RequiresMountsFor={}", OVERLAY_STORE)],
+        "Service",
+        &["Type=oneshot".to_owned(), "RemainAfterExit=yes".to_owned(), format!("ExecStart=/bin/mkdir -p {}", dirs.join(" "))],
+    ), false);
+    for mount in &state.overlays {
+        mountpoint(&mount.path);
+        let upper = format!("{}{}", OVERLAY_STORE, mount.path);
+        write_unit(&mount_unit(&mount.path), &unit(
+            &format!("Writable {}", mount.path),
+            &[format!("Requires={0}\nAfter={0}", OVERLAY_DIRS_UNIT)],
+            "Mount",
+            &[
+                "What=overlay".to_owned(),
+                format!("Where={}", mount.path),
+                "Type=overlay".to_owned(),
+                format!("Options=lowerdir={0},upperdir={1}/upper,workdir={1}/work", mount.path, upper),
+            ],
+        ), true);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_unit_escapes_like_systemd() {
+        assert_eq!(mount_unit("/var/lib/postgresql"), "var-lib-postgresql.mount");
+        assert_eq!(mount_unit("/var/lib/foo-bar"), "var-lib-foo\\x2dbar.mount");
+        assert_eq!(mount_unit("/.hidden"), "\\x2ehidden.mount");
+        assert_eq!(mount_unit("/var/.cache"), "var-.cache.mount");
+        assert_eq!(mount_unit("/srv/a_b.c"), "srv-a_b.c.mount");
+    }
+}
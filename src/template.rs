@@ -0,0 +1,27 @@
+use std::collections::BTreeMap;
+use crate::*;
+
+/// Replace every `{{ name }}` in the template with its value in `vars`.
+/// Unknown names halt rather than render as empty, so a typo cannot ship a broken config file.
+pub fn render(source: &str, template: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").unwrap_or_else(|| halt!("Unterminated '{{{{' in template {}", source));
+        let name = after[..end].trim();
+        let value = vars.get(name).unwrap_or_else(|| halt!(
+            "Unknown variable '{}' in template {}. Available variables are: {}",
+            name,
+            source,
+            vars.keys().cloned().collect::<Vec<_>>().join(", ")
+        ));
+        output.push_str(value);
+        rest = &after[end + 2..];
+    }
+
+    output.push_str(rest);
+    output
+}
@@ -0,0 +1,382 @@
+use std::fs;
+use std::process::Command;
+use std::collections::HashSet;
+use crate::*;
+use crate::machine::get_machine;
+use crate::vm::NetLink;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Serialize, Deserialize};
+
+/// Linux limits interface names to 15 characters.
+const IFNAME_MAX: usize = 15;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Topology {
+    pub name: String,
+    #[serde(default)]
+    pub switches: Vec<Switch>,
+    pub machines: Vec<Member>,
+}
+
+/// A virtual L2 segment, implemented as a VLAN-filtering bridge on the VM host.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Switch {
+    pub name: String,
+    #[serde(default)]
+    pub vlans: Vec<u16>,
+    /// Host interface bridged into the switch as a tagged trunk for all of its VLANs
+    #[serde(default)]
+    pub uplink: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Member {
+    pub machine: String,
+    #[serde(default)]
+    pub memory: Option<u32>,
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    /// Machines that must be up before this one is started
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+/// Connects one of the machine's captured NICs to a switch, untagged on `vlan` if given.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Link {
+    pub nic: String,
+    pub switch: String,
+    #[serde(default)]
+    pub vlan: Option<u16>,
+}
+
+
+fn topologies_db() -> sled::Tree {
+    database().open_tree("topologies").unwrap_or_else(|e| halt!("Could not open topologies database tree: {}", e))
+}
+
+fn bridge_name(switch: &str) -> String {
+    format!("rbr-{}", switch)
+}
+
+fn tap_name(machine: &str, index: usize) -> String {
+    format!("rt-{}-{}", machine, index)
+}
+
+fn ip(args: &[&str]) -> Command {
+    let ip_path = which("ip");
+    let mut ip_args = vec![&ip_path[..]];
+    ip_args.extend_from_slice(args);
+    pkexec(&ip_args)
+}
+
+fn bridge(args: &[&str]) -> Command {
+    let bridge_path = which("bridge");
+    let mut bridge_args = vec![&bridge_path[..]];
+    bridge_args.extend_from_slice(args);
+    pkexec(&bridge_args)
+}
+
+fn link_show(ifname: &str) -> Command {
+    let mut command = Command::new(which("ip"));
+    command.args(["link", "show", ifname]);
+    command
+}
+
+/// Succeeds if the interface is not a member of the VLAN.
+fn vlan_absent(ifname: &str, vid: u16) -> Command {
+    let mut command = Command::new("sh");
+    command.args([
+        "-c",
+        &format!("! \"$0\" -j vlan show dev \"$1\" | grep -q '\"vlan\":{}[,}}]'", vid),
+        &which("bridge"),
+        ifname,
+    ]);
+    command
+}
+
+fn link_exists(ifname: &str) -> bool {
+    success_stauts(link_show(ifname))
+}
+
+/// The bridge or bond the interface is enslaved to, if any.
+fn master(ifname: &str) -> Option<String> {
+    fs::read_link(format!("/sys/class/net/{}/master", ifname)).ok()
+        .and_then(|target| target.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+
+pub fn define(file: &str) {
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| halt!("Could not read topology file {}: {}", file, e));
+    let topology: Topology = serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse topology file {}: {}", file, e));
+    validate(&topology);
+
+    let config = PrettyConfig::new()
+        .struct_names(true)
+        .compact_arrays(false);
+    let data = to_string_pretty(&topology, config).unwrap_or_else(|e| halt!("Could not serialize data: {}", e));
+
+    let db = topologies_db();
+    db.insert(topology.name.as_bytes(), data.as_bytes()).unwrap_or_else(|e| halt!("Could not insert data: {}", e));
+    db.flush().unwrap_or_else(|e| halt!("Error flushing database: {}", e));
+    log!("Defined topology {} with {} machine(s) and {} switch(es)", topology.name, topology.machines.len(), topology.switches.len());
+}
+
+
+fn validate(topology: &Topology) {
+    check(topology).unwrap_or_else(|e| halt!("{}", e));
+
+    for member in &topology.machines {
+        let machine = get_machine(&member.machine).unwrap_or_else(|| halt!("Topology {} refers to unknown machine {}", topology.name, member.machine));
+        for link in &member.links {
+            if !machine.nics.iter().any(|n| n.name == link.nic) {
+                halt!("Machine {} has no captured NIC named {}", member.machine, link.nic);
+            }
+        }
+    }
+}
+
+/// The checks of a topology that do not need its machines' records.
+fn check(topology: &Topology) -> Result<(), String> {
+    let members: HashSet<&str> = topology.machines.iter().map(|m| m.machine.as_str()).collect();
+
+    for switch in &topology.switches {
+        if bridge_name(&switch.name).len() > IFNAME_MAX {
+            return Err(format!("Switch name {} is too long, bridge name {} must be at most {} characters", switch.name, bridge_name(&switch.name), IFNAME_MAX));
+        }
+        if let Some(vlan) = switch.vlans.iter().find(|v| **v == 0 || **v > 4094) {
+            return Err(format!("Switch {} has invalid VLAN {}, VLAN IDs must be between 1 and 4094", switch.name, vlan));
+        }
+    }
+
+    for member in &topology.machines {
+        for dependency in &member.depends_on {
+            if !members.contains(dependency.as_str()) {
+                return Err(format!("Machine {} depends on {}, which is not part of topology {}", member.machine, dependency, topology.name));
+            }
+        }
+
+        for (i, link) in member.links.iter().enumerate() {
+            if tap_name(&member.machine, i).len() > IFNAME_MAX {
+                return Err(format!("Machine name {} is too long, tap name {} must be at most {} characters", member.machine, tap_name(&member.machine, i), IFNAME_MAX));
+            }
+            let switch = topology.switches.iter().find(|s| s.name == link.switch)
+                .ok_or_else(|| format!("Machine {} links to unknown switch {}", member.machine, link.switch))?;
+            if let Some(vlan) = link.vlan {
+                if !switch.vlans.contains(&vlan) {
+                    return Err(format!("Machine {} uses VLAN {} which is not defined on switch {}", member.machine, vlan, switch.name));
+                }
+            }
+        }
+    }
+
+    order(topology).map(|_| ())
+}
+
+
+/// Members sorted so that every machine comes after the machines it depends on.
+fn start_order(topology: &Topology) -> Vec<&Member> {
+    order(topology).unwrap_or_else(|e| halt!("{}", e))
+}
+
+fn order(topology: &Topology) -> Result<Vec<&Member>, String> {
+    let mut ordered: Vec<&Member> = Vec::new();
+    let mut placed: HashSet<&str> = HashSet::new();
+
+    while ordered.len() < topology.machines.len() {
+        let ready: Vec<&Member> = topology.machines.iter()
+            .filter(|m| !placed.contains(m.machine.as_str()))
+            .filter(|m| m.depends_on.iter().all(|d| placed.contains(d.as_str())))
+            .collect();
+        if ready.is_empty() {
+            return Err(format!("Topology {} has circular machine dependencies", topology.name));
+        }
+        for member in ready {
+            placed.insert(&member.machine);
+            ordered.push(member);
+        }
+    }
+
+    Ok(ordered)
+}
+
+
+pub fn get_topology(name: &str) -> Option<Topology> {
+    let bytes = topologies_db().get(name.as_bytes()).unwrap_or_else(|e| halt!("Could not retreive data for topology {} : {}", name, e))?;
+    let string = String::from_utf8_lossy(&bytes);
+    ron::from_str(&string).ok()
+}
+
+pub fn list() -> Vec<String> {
+    topologies_db().iter()
+        .map(|item| item.unwrap_or_else(|e| halt!("Error iterating topologies tree: {}", e)).0)
+        .map(|key| String::from_utf8_lossy(&key).into_owned())
+        .collect()
+}
+
+
+pub fn up(name: &str) {
+    let topology = get_topology(name).unwrap_or_else(|| halt!("No topology named {}. Use 'ref topology define <file>' to create it.", name));
+
+    for switch in &topology.switches {
+        let bridge_if = bridge_name(&switch.name);
+        perform(
+            &format!("Create switch {}", switch.name),
+            Some(link_show(&bridge_if)),
+            ip(&["link", "add", "name", &bridge_if, "type", "bridge", "vlan_filtering", "1"]),
+            false
+        );
+        perform(&format!("Bring up switch {}", switch.name), None, ip(&["link", "set", &bridge_if, "up"]), false);
+
+        if let Some(uplink) = &switch.uplink {
+            perform(&format!("Attach uplink {} to switch {}", uplink, switch.name), None, ip(&["link", "set", uplink, "master", &bridge_if]), false);
+            for vlan in &switch.vlans {
+                perform(
+                    &format!("Trunk VLAN {} on uplink {}", vlan, uplink),
+                    None,
+                    bridge(&["vlan", "add", "dev", uplink, "vid", &vlan.to_string()]),
+                    false
+                );
+            }
+        }
+    }
+
+    for member in start_order(&topology) {
+        let machine = get_machine(&member.machine).unwrap_or_else(|| halt!("No data found for machine {}", member.machine));
+        let mut links = Vec::new();
+
+        for (i, link) in member.links.iter().enumerate() {
+            let tap = tap_name(&member.machine, i);
+            perform(
+                &format!("Create tap {} for {} {}", tap, member.machine, link.nic),
+                Some(link_show(&tap)),
+                ip(&["tuntap", "add", "dev", &tap, "mode", "tap"]),
+                false
+            );
+            perform(&format!("Connect {} to switch {}", tap, link.switch), None, ip(&["link", "set", &tap, "master", &bridge_name(&link.switch)]), false);
+            perform(&format!("Bring up {}", tap), None, ip(&["link", "set", &tap, "up"]), false);
+
+            if let Some(vlan) = link.vlan {
+                perform(
+                    &format!("Remove default VLAN from {}", tap),
+                    Some(vlan_absent(&tap, 1)),
+                    bridge(&["vlan", "del", "dev", &tap, "vid", "1"]),
+                    false
+                );
+                perform(
+                    &format!("Set {} untagged on VLAN {}", tap, vlan),
+                    None,
+                    bridge(&["vlan", "add", "dev", &tap, "vid", &vlan.to_string(), "pvid", "untagged"]),
+                    false
+                );
+            }
+
+            let nic = machine.nics.iter().find(|n| n.name == link.nic)
+                .unwrap_or_else(|| halt!("Machine {} has no captured NIC named {}", member.machine, link.nic));
+            links.push(NetLink { tap, mac: nic.mac.clone() });
+        }
+
+        vm::start(
+            &machine,
+            member.memory.unwrap_or(vm::DEFAULT_MEMORY),
+            member.cpus.unwrap_or(vm::DEFAULT_CPUS),
+            &links
+        );
+    }
+
+    log!("Topology {} is up", name);
+}
+
+
+pub fn down(name: &str) {
+    let topology = get_topology(name).unwrap_or_else(|| halt!("No topology named {}", name));
+
+    for member in start_order(&topology).into_iter().rev() {
+        vm::stop(&member.machine);
+        for i in 0..member.links.len() {
+            let tap = tap_name(&member.machine, i);
+            if link_exists(&tap) {
+                perform(&format!("Delete tap {}", tap), None, ip(&["link", "del", &tap]), false);
+            }
+        }
+    }
+
+    for switch in &topology.switches {
+        let bridge_if = bridge_name(&switch.name);
+        if let Some(uplink) = switch.uplink.as_ref().filter(|uplink| master(uplink).as_ref() == Some(&bridge_if)) {
+            perform(&format!("Detach uplink {} from switch {}", uplink, switch.name), None, ip(&["link", "set", uplink, "nomaster"]), false);
+        }
+        if link_exists(&bridge_if) {
+            perform(&format!("Delete switch {}", switch.name), None, ip(&["link", "del", &bridge_if]), false);
+        }
+    }
+
+    log!("Topology {} is down", name);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(yaml: &str) -> Topology {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn names(members: Vec<&Member>) -> Vec<&str> {
+        members.iter().map(|m| m.machine.as_str()).collect()
+    }
+
+    #[test]
+    fn start_order_follows_dependencies() {
+        let cases = [
+            ("{name: t, machines: [{machine: a}, {machine: b}]}", vec!["a", "b"]),
+            ("{name: t, machines: [{machine: web, depends_on: [db]}, {machine: db}]}", vec!["db", "web"]),
+            ("{name: t, machines: [{machine: c, depends_on: [b]}, {machine: b, depends_on: [a]}, {machine: a}]}", vec!["a", "b", "c"]),
+            ("{name: t, machines: [{machine: d, depends_on: [b, c]}, {machine: c, depends_on: [a]}, {machine: b, depends_on: [a]}, {machine: a}]}", vec!["a", "c", "b", "d"]),
+            ("{name: t, machines: []}", vec![]),
+        ];
+        for (yaml, expected) in cases {
+            assert_eq!(names(order(&topology(yaml)).unwrap()), expected, "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn circular_dependencies_are_rejected() {
+        for yaml in [
+            "{name: t, machines: [{machine: a, depends_on: [a]}]}",
+            "{name: t, machines: [{machine: a, depends_on: [b]}, {machine: b, depends_on: [a]}]}",
+            "{name: t, machines: [{machine: a}, {machine: b, depends_on: [c]}, {machine: c, depends_on: [d]}, {machine: d, depends_on: [b]}]}",
+        ] {
+            assert!(order(&topology(yaml)).unwrap_err().contains("circular"), "{}", yaml);
+            assert!(check(&topology(yaml)).unwrap_err().contains("circular"), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn check_accepts_valid_topologies() {
+        let yaml = "{name: t, switches: [{name: lan, vlans: [1, 10, 4094], uplink: eth1}], machines: [
+            {machine: db, links: [{nic: eth0, switch: lan, vlan: 10}]},
+            {machine: web, depends_on: [db], links: [{nic: eth0, switch: lan}, {nic: eth1, switch: lan, vlan: 4094}]}]}";
+        assert_eq!(check(&topology(yaml)), Ok(()));
+    }
+
+    #[test]
+    fn check_rejects_invalid_topologies() {
+        let cases = [
+            ("{name: t, machines: [{machine: a, links: [{nic: eth0, switch: wan}]}]}", "unknown switch wan"),
+            ("{name: t, switches: [{name: lan}], machines: [{machine: a, links: [{nic: eth0, switch: lan, vlan: 10}]}]}", "VLAN 10 which is not defined"),
+            ("{name: t, machines: [{machine: a, depends_on: [b]}]}", "not part of topology"),
+            ("{name: t, switches: [{name: lan, vlans: [0]}], machines: []}", "invalid VLAN 0"),
+            ("{name: t, switches: [{name: lan, vlans: [4095]}], machines: []}", "invalid VLAN 4095"),
+            ("{name: t, switches: [{name: verylongname}], machines: []}", "too long"),
+            ("{name: t, switches: [{name: lan}], machines: [{machine: verylongnam, links: [{nic: eth0, switch: lan}]}]}", "too long"),
+        ];
+        for (yaml, error) in cases {
+            let result = check(&topology(yaml));
+            assert!(result.as_ref().is_err_and(|e| e.contains(error)), "{}: {:?}", yaml, result);
+        }
+    }
+}
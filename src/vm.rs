@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use crate::*;
+use crate::machine::Machine;
+
+const VM_DIR: &str = "/opt/reflectron/vms";
+
+const OVMF_FIRMWARE: &[(&str, &str)] = &[
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd"),
+];
+
+pub const DEFAULT_MEMORY: u32 = 4096;
+pub const DEFAULT_CPUS: u32 = 2;
+
+/// A tap device connecting one of the VM's NICs to a virtual switch.
+pub struct NetLink {
+    pub tap: String,
+    pub mac: String,
+}
+
+
+fn firmware() -> (&'static str, &'static str) {
+    for (code, vars) in OVMF_FIRMWARE {
+        if Path::new(code).exists() && Path::new(vars).exists() {
+            return (code, vars);
+        }
+    }
+    halt!("Could not find OVMF firmware in /usr/share/OVMF. Please install the ovmf package and try again.");
+}
+
+
+fn vm_dir(machine_name: &str) -> String {
+    let dir = format!("{}/{}", VM_DIR, machine_name);
+    fs::create_dir_all(&dir).unwrap_or_else(|e| halt!("Failed to create VM directory {}: {}", dir, e));
+    dir
+}
+
+
+/// PIDs of running QEMU processes for the machine. Matched on the -name argument
+/// we launch every VM with, so VMs started outside reflectron are never touched.
+pub fn pids(machine_name: &str) -> Vec<String> {
+    let pattern = format!("-name reflectron-{} ", machine_name);
+    match Command::new(which("pgrep")).args(["-f", "--", &pattern]).output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        Err(e) => halt!("Could not check for running VM {}: {}", machine_name, e),
+    }
+}
+
+pub fn is_running(machine_name: &str) -> bool {
+    !pids(machine_name).is_empty()
+}
+
+
+pub fn start(machine: &Machine, memory: u32, cpus: u32, links: &[NetLink]) {
+    if is_running(&machine.name) {
+        log!("VM {} is already running, skipping.", machine.name);
+        return;
+    }
+
+    // the VM's pool must not be imported on the host while the VM uses it
+    install::export(machine);
+
+    let (code, vars) = firmware();
+    let dir = vm_dir(&machine.name);
+    let vars_path = format!("{}/OVMF_VARS.fd", dir);
+    if !Path::new(&vars_path).exists() {
+        fs::copy(vars, &vars_path).unwrap_or_else(|e| halt!("Failed to copy UEFI variable store to {}: {}", vars_path, e));
+    }
+
+    let name = format!("reflectron-{}", machine.name);
+    let memory = memory.to_string();
+    let cpus = cpus.to_string();
+    let code_drive = format!("if=pflash,format=raw,readonly=on,file={}", code);
+    let vars_drive = format!("if=pflash,format=raw,file={}", vars_path);
+    let serial = format!("unix:{}/console.sock,server,nowait", dir);
+
+    let mut args: Vec<String> = vec![
+        which("qemu-system-x86_64"),
+        "-name".into(), name,
+        "-machine".into(), "q35,accel=kvm".into(),
+        "-cpu".into(), "host".into(),
+        "-m".into(), memory,
+        "-smp".into(), cpus,
+        "-display".into(), "none".into(),
+        "-daemonize".into(),
+        "-serial".into(), serial,
+        "-drive".into(), code_drive,
+        "-drive".into(), vars_drive,
+    ];
+
+    for disk in &machine.disks {
+        args.push("-drive".into());
+        args.push(format!("file=/dev/zvol/{},format=raw,if=virtio,cache=none", disk::zvol_dataset(machine, disk)));
+    }
+
+    for (i, link) in links.iter().enumerate() {
+        args.push("-netdev".into());
+        args.push(format!("tap,id=net{},ifname={},script=no,downscript=no", i, link.tap));
+        args.push("-device".into());
+        args.push(format!("virtio-net-pci,netdev=net{},mac={}", i, link.mac));
+    }
+
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    perform(&format!("Start VM {}", machine.name), None, pkexec(&args), false);
+}
+
+
+pub fn stop(machine_name: &str) {
+    let pids = pids(machine_name);
+    if pids.is_empty() {
+        log!("VM {} is not running, skipping.", machine_name);
+        return;
+    }
+    for pid in pids {
+        perform(
+            &format!("Stop VM {} (pid {})", machine_name, pid),
+            None,
+            pkexec(&[&which("kill"), "-TERM", &pid]),
+            false
+        );
+    }
+}
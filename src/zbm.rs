@@ -0,0 +1,430 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use crate::*;
+use crate::image::manifest::sha256_hex;
+use crate::machine::Machine;
+use crate::settings::Key;
+
+const ZBM_DIR: &str = "/opt/reflectron/zbm";
+const RELEASE_URL: &str = "https://get.zfsbootmenu.org/efi";
+
+/// Where ZFSBootMenu lives on every ESP, and the firmware's fallback loader path for VMs.
+pub const EFI_PATH: &str = "/EFI/ZBM/VMLINUZ.EFI";
+pub const FALLBACK_EFI_PATH: &str = "/EFI/BOOT/BOOTX64.EFI";
+/// Run by the OVMF shell when no boot entry works, since VMs start with an empty NVRAM.
+pub const STARTUP_NSH: &str = "\\EFI\\ZBM\\VMLINUZ.EFI\r\n";
+
+const ESP_PARTTYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+const REMOTE_ESP_MOUNT: &str = "/tmp/reflectron-esp";
+
+pub const DEFAULT_COMMANDLINE: &str = "quiet loglevel=4";
+
+const BUILDER_IMAGE: &str = "ghcr.io/zbm-dev/zbm-builder:latest";
+pub const DEFAULT_UNLOCK_PORT: u16 = 222;
+/// Initramfs of remote unlock builds, with networking and dropbear ahead of ZFSBootMenu.
+const MKINITCPIO_CONF: &str = "# Managed by reflectron
+MODULES=()
+BINARIES=()
+FILES=()
+HOOKS=(base udev autodetect modconf block filesystems keyboard net dropbear zfsbootmenu)
+COMPRESSION=\"zstd\"
+";
+/// How long to wait for a test VM's ZFSBootMenu to start its SSH server.
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+
+/// How ZFSBootMenu boots the machine's boot environments.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Boot {
+    /// Kernel command line, set as org.zfsbootmenu:commandline on the boot environment container
+    pub commandline: String,
+    /// SSH access to ZFSBootMenu, to unlock encrypted pools remotely
+    #[serde(default)]
+    pub remote_unlock: Option<RemoteUnlock>,
+    /// Checksum of the machine's ZFSBootMenu build last installed on its test VM
+    #[serde(default)]
+    pub vm_image: Option<String>,
+    /// Checksum of the machine's ZFSBootMenu build that was reached over SSH in its test VM.
+    /// Only a verified build is installed in production.
+    #[serde(default)]
+    pub verified: Option<String>,
+}
+
+impl Default for Boot {
+    fn default() -> Boot {
+        Boot {
+            commandline: DEFAULT_COMMANDLINE.to_owned(),
+            remote_unlock: None,
+            vm_image: None,
+            verified: None,
+        }
+    }
+}
+
+/// A dropbear SSH server in ZFSBootMenu, listening on one of the machine's NICs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteUnlock {
+    /// Name of the captured NIC to configure
+    pub nic: String,
+    /// Use DHCP instead of the NIC's captured address and the machine's gateway
+    #[serde(default)]
+    pub dhcp: bool,
+    #[serde(default = "default_unlock_port")]
+    pub port: u16,
+    /// Public keys allowed to log in as root, one OpenSSH authorized_keys line each
+    pub authorized_keys: Vec<String>,
+}
+
+fn default_unlock_port() -> u16 {
+    DEFAULT_UNLOCK_PORT
+}
+
+pub fn check_commandline(commandline: &str) {
+    if !commandline.chars().all(|c| c.is_ascii_alphanumeric() || " =._-,:/".contains(c)) {
+        halt!("Invalid kernel command line '{}'", commandline);
+    }
+}
+
+/// Set the machine's kernel command line. It takes effect on the next 'ref install', 'ref zbm install'
+/// or deploy.
+pub fn set_commandline(machine_name: &str, commandline: &str) {
+    check_commandline(commandline);
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    machine.boot.commandline = commandline.to_owned();
+    machine::save_machine(&machine);
+    log!("Set kernel command line of {} to '{}'", machine_name, commandline);
+}
+
+
+/// Load a machine's remote unlock file and store it with the machine. The machine's
+/// ZFSBootMenu has to be built and verified again before it is installed in production.
+pub fn set_remote_unlock(machine_name: &str, file: &str) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let contents = fs::read_to_string(file).unwrap_or_else(|e| halt!("Could not read remote unlock file {}: {}", file, e));
+    let unlock: RemoteUnlock = serde_yaml::from_str(&contents).unwrap_or_else(|e| halt!("Could not parse remote unlock file {}: {}", file, e));
+    if !machine.nics.iter().any(|n| n.name == unlock.nic) {
+        halt!("Machine {} has no NIC named {}", machine_name, unlock.nic);
+    }
+    if unlock.authorized_keys.is_empty() {
+        halt!("Remote unlock needs at least one authorized key");
+    }
+    for key in &unlock.authorized_keys {
+        access::check_key("root", key);
+    }
+    machine.boot.remote_unlock = Some(unlock);
+    machine.boot.verified = None;
+    machine::save_machine(&machine);
+    log!("Set remote unlock for machine {}. Run 'ref zbm build {}' to build its ZFSBootMenu.", machine_name, machine_name);
+}
+
+
+/// Where a machine's own ZFSBootMenu is built.
+fn build_dir(machine_name: &str) -> String {
+    format!("{}/{}/zbm", overlay::MACHINES_DIR, machine_name)
+}
+
+fn built_image_path(machine_name: &str) -> String {
+    format!("{}/build/vmlinuz.EFI", build_dir(machine_name))
+}
+
+
+/// The ip= kernel argument bringing up the unlock NIC in the initramfs.
+fn ip_argument(machine: &Machine, unlock: &RemoteUnlock) -> String {
+    let nic = machine.nics.iter().find(|n| n.name == unlock.nic)
+        .unwrap_or_else(|| halt!("Machine {} has no NIC named {}", machine.name, unlock.nic));
+    if unlock.dhcp {
+        return format!("ip=:::::{}:dhcp", nic.name);
+    }
+    let address = nic.addresses.iter().find(|a| !a.contains(':'))
+        .unwrap_or_else(|| halt!("NIC {} of {} has no IPv4 address. Use DHCP for remote unlock instead.", nic.name, machine.name));
+    let (ip, prefix) = address.split_once('/').unwrap_or((address, "32"));
+    let prefix: u32 = prefix.parse().ok().filter(|p| *p <= 32)
+        .unwrap_or_else(|| halt!("Invalid address {} on NIC {}", address, nic.name));
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    let netmask = std::net::Ipv4Addr::from(mask);
+    let gateway = nic.gateway.clone().or_else(|| machine.nics.iter().find_map(|n| n.gateway.clone())).unwrap_or_default();
+    format!("ip={}::{}:{}:{}:{}:none", ip, gateway, netmask, machine.name, nic.name)
+}
+
+
+/// Build the machine's own ZFSBootMenu with a dropbear SSH server, in the zbm-builder container.
+/// The SSH host key is generated once, so clients can pin it.
+pub fn build(machine_name: &str) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let unlock = machine.boot.remote_unlock.clone()
+        .unwrap_or_else(|| halt!("Machine {} has no remote unlock settings. Set them with 'ref zbm remote-unlock'.", machine_name));
+    check_commandline(&machine.boot.commandline);
+
+    let dir = build_dir(machine_name);
+    let dropbear = format!("{}/dropbear", dir);
+    fs::create_dir_all(format!("{}/build", dir)).unwrap_or_else(|e| halt!("Could not create build directory {}: {}", dir, e));
+    fs::create_dir_all(&dropbear).unwrap_or_else(|e| halt!("Could not create build directory {}: {}", dropbear, e));
+
+    let host_key = format!("{}/ssh_host_ed25519_key", dropbear);
+    if !Path::new(&host_key).exists() {
+        let mut keygen = Command::new(which("ssh-keygen"));
+        keygen.args(["-q", "-t", "ed25519", "-N", "", "-C", &format!("{}-zfsbootmenu", machine_name), "-f", &host_key]);
+        perform("Generate ZFSBootMenu SSH host key", None, keygen, false);
+    }
+
+    let write = |path: String, contents: &str| {
+        fs::write(&path, contents).unwrap_or_else(|e| halt!("Could not write {}: {}", path, e));
+    };
+    // zbm-builder installs the dropbear directory as /etc/dropbear in the initramfs
+    write(format!("{}/root_key", dropbear), &format!("{}\n", unlock.authorized_keys.join("\n")));
+    write(format!("{}/dropbear.conf", dropbear), &format!("DROPBEAR_OPTIONS=\"-p {} -s -j -k\"\n", unlock.port));
+    write(format!("{}/mkinitcpio.conf", dir), MKINITCPIO_CONF);
+    write(format!("{}/config.yaml", dir), &format!(
+        "# Managed by reflectron\n\
+        Global:\n  ManageImages: true\n  InitCPIO: true\n  InitCPIOConfig: /build/mkinitcpio.conf\n\
+        Components:\n  Enabled: false\n\
+        EFI:\n  Enabled: true\n  ImageDir: /build/build\n  Versions: false\n\
+        Kernel:\n  Prefix: vmlinuz\n  CommandLine: {} {}\n",
+        machine.boot.commandline, ip_argument(&machine, &unlock)
+    ));
+
+    let mut podman = Command::new(which("podman"));
+    podman.args(["run", "--rm", "-v", &format!("{}:/build", dir), BUILDER_IMAGE]);
+    perform(&format!("Build ZFSBootMenu for {}", machine_name), None, podman, true);
+
+    let image = fs::read(built_image_path(machine_name))
+        .unwrap_or_else(|e| halt!("ZFSBootMenu build for {} produced no image at {}: {}", machine_name, built_image_path(machine_name), e));
+    machine.boot.verified = None;
+    machine::save_machine(&machine);
+    log!(
+        "Built ZFSBootMenu {} for {}. Install it with 'ref zbm install {} --vm' and check it with 'ref zbm verify {}'.",
+        sha256_hex(&image), machine_name, machine_name, machine_name
+    );
+}
+
+
+/// Wait for the test VM's ZFSBootMenu to accept SSH connections, log in with the ssh-agent and
+/// unlock the root pool with the machine's key, before the build may be installed in production.
+pub fn verify(machine_name: &str, address: Option<&str>) {
+    let mut machine = machine::get_machine(machine_name).unwrap_or_else(|| halt!("No machine named {}", machine_name));
+    let unlock = machine.boot.remote_unlock.clone()
+        .unwrap_or_else(|| halt!("Machine {} has no remote unlock settings", machine_name));
+    let encryption = machine.encryption.clone()
+        .unwrap_or_else(|| halt!("Machine {} is not encrypted, so there is nothing to unlock. Create a key with 'ref key create {}'.", machine_name, machine_name));
+    let checksum = sha256_hex(&efi_image(&machine));
+    if machine.boot.vm_image.as_deref() != Some(checksum.as_str()) {
+        halt!("The VM of {} does not have the current ZFSBootMenu build. Run 'ref zbm install {} --vm' first.", machine_name, machine_name);
+    }
+    if !vm::is_running(machine_name) {
+        halt!("VM {} is not running. Start it, e.g. with 'ref topology up', and retry.", machine_name);
+    }
+
+    let address = match address {
+        Some(address) => address.to_owned(),
+        None if unlock.dhcp => halt!("Machine {} uses DHCP for remote unlock. Give the VM's address with --address.", machine_name),
+        None => {
+            let ip = ip_argument(&machine, &unlock);
+            format!("{}:{}", ip.trim_start_matches("ip=").split(':').next().unwrap_or(""), unlock.port)
+        }
+    };
+    let socket = address.to_socket_addrs().ok().and_then(|mut a| a.next())
+        .unwrap_or_else(|| halt!("Invalid address {}", address));
+
+    log!("Waiting for ZFSBootMenu on {} to accept SSH connections at {}", machine_name, address);
+    let started = Instant::now();
+    while TcpStream::connect_timeout(&socket, Duration::from_secs(5)).is_err() {
+        if started.elapsed() > UNLOCK_TIMEOUT {
+            halt!("ZFSBootMenu on {} did not accept connections at {} within {} seconds", machine_name, address, UNLOCK_TIMEOUT.as_secs());
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+
+    let sess = machine::agent_session(machine_name, &address);
+    let pools = machine::remote_run(&sess, &address, "List pools in ZFSBootMenu", "zpool list -H -o name");
+    if !pools.lines().any(|p| p.trim() == overlay::ROOT_POOL) {
+        halt!("ZFSBootMenu on {} did not import pool {}. Pools found: {}", machine_name, overlay::ROOT_POOL, pools.trim());
+    }
+
+    // the key is fed to a prompt, as an operator would type it, whatever its keylocation. A dry
+    // run checks it when the pool has already been unlocked.
+    let encryption_root = format!("{}/{}", overlay::ROOT_POOL, install::BOOT_CONTAINER);
+    let keystatus = machine::remote_run(&sess, &address, "Get key status", &format!("zfs get -H -o value keystatus {}", encryption_root));
+    let dry_run = if keystatus.trim() == "available" { "-n " } else { "" };
+    let mut key = fs::read(keys::key_path(machine_name))
+        .unwrap_or_else(|e| halt!("Could not read key of {} at {}: {}", machine_name, keys::key_path(machine_name), e));
+    if encryption.keyformat == "passphrase" {
+        key.push(b'\n');
+    }
+    machine::remote_run_with_input(
+        &sess, &address,
+        &format!("Unlock {} in ZFSBootMenu", encryption_root),
+        &format!("zfs load-key {}-L prompt {}", dry_run, encryption_root),
+        &key
+    );
+
+    machine.boot.verified = Some(checksum);
+    machine::save_machine(&machine);
+    log!("Verified remote access to ZFSBootMenu on VM {}", machine_name);
+}
+
+
+/// The ZFSBootMenu EFI image for the machine: its own build if it has remote unlock, else the
+/// zbm-efi setting if set, else the upstream release, downloaded once and cached.
+pub fn efi_image(machine: &Machine) -> Vec<u8> {
+    if machine.boot.remote_unlock.is_some() {
+        let path = built_image_path(&machine.name);
+        return fs::read(&path)
+            .unwrap_or_else(|_| halt!("Machine {} has remote unlock but no ZFSBootMenu build. Run 'ref zbm build {}'.", machine.name, machine.name));
+    }
+    let path = match settings::get(Key::ZbmEfi) {
+        Some(path) => path,
+        None => {
+            let path = format!("{}/release-x86_64.EFI", ZBM_DIR);
+            if !Path::new(&path).is_file() {
+                fs::create_dir_all(ZBM_DIR).unwrap_or_else(|e| halt!("Could not create {}: {}", ZBM_DIR, e));
+                let url = format!("{}/release", RELEASE_URL);
+                let mut curl = Command::new(which("curl"));
+                curl.args(["-fsSL", "-o", &path, &url]);
+                perform(&format!("Download ZFSBootMenu from {}", url), None, curl, false);
+            }
+            path
+        }
+    };
+    fs::read(&path).unwrap_or_else(|e| halt!("Could not read ZFSBootMenu EFI image {}: {}", path, e))
+}
+
+
+/// Install ZFSBootMenu on an ESP mounted locally at `esp`, for a test VM.
+pub fn install_local(esp: &str, efi: &[u8]) {
+    image::install_file(esp, EFI_PATH, "644", efi);
+    image::install_file(esp, FALLBACK_EFI_PATH, "644", efi);
+    image::install_file(esp, "/startup.nsh", "644", STARTUP_NSH);
+}
+
+
+/// The ESPs of a remote machine as (partition, disk, partition number).
+fn remote_esps(sess: &ssh2::Session, address: &str) -> Vec<(String, String, String)> {
+    let script = format!(
+        "for dev in $(lsblk -rno PATH,PARTTYPE | awk '$2 == \"{}\" {{ print $1 }}'); do \
+            echo \"$dev /dev/$(lsblk -no PKNAME $dev) $(cat /sys/class/block/$(basename $dev)/partition)\"; \
+        done",
+        ESP_PARTTYPE
+    );
+    machine::remote_run(sess, address, "Find ESPs", &script)
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?.to_owned(), parts.next()?.to_owned(), parts.next()?.to_owned()))
+        })
+        .collect()
+}
+
+
+/// Install ZFSBootMenu on every ESP of the production machine, add a boot entry for each
+/// and check that all ESPs hold the same image.
+pub fn install_remote(machine: &Machine) {
+    let efi = efi_image(machine);
+    let checksum = sha256_hex(&efi);
+    if machine.boot.remote_unlock.is_some() && machine.boot.verified.as_deref() != Some(checksum.as_str()) {
+        halt!("ZFSBootMenu build for {} has not been verified in its test VM. Run 'ref zbm verify {}' first.", machine.name, machine.name);
+    }
+    let (sess, address) = machine::session(machine);
+
+    let esps = remote_esps(&sess, &address);
+    if esps.is_empty() {
+        halt!("Machine {} has no EFI system partitions", machine.name);
+    }
+
+    let entries = machine::remote_run(&sess, &address, "List EFI boot entries", "efibootmgr");
+    for (partition, disk, number) in &esps {
+        machine::remote_run(&sess, &address, &format!("Mount ESP {}", partition), &format!(
+            "mkdir -p {0} && mount {1} {0} && mkdir -p {0}/EFI/ZBM", REMOTE_ESP_MOUNT, partition
+        ));
+        machine::remote_write(&sess, &address, &format!("{}{}", REMOTE_ESP_MOUNT, EFI_PATH), 0o644, &efi);
+        let installed = machine::remote_run(&sess, &address, &format!("Check ZFSBootMenu on {}", partition), &format!(
+            "sha256sum {0}{1} && umount {0}", REMOTE_ESP_MOUNT, EFI_PATH
+        ));
+        if !installed.starts_with(&checksum) {
+            halt!("ZFSBootMenu on ESP {} of {} does not match the image installed", partition, machine.name);
+        }
+
+        let label = format!("ZFSBootMenu ({})", disk.trim_start_matches("/dev/"));
+        if !entries.contains(&label) {
+            machine::remote_run(&sess, &address, &format!("Add EFI boot entry {}", label), &format!(
+                "efibootmgr -c -d {} -p {} -L '{}' -l '{}'", disk, number, label, EFI_PATH.replace('/', "\\")
+            ));
+        }
+    }
+
+    set_remote_properties(machine, &sess, &address);
+    log!("Installed ZFSBootMenu on {} ESP(s) of {}", esps.len(), machine.name);
+}
+
+
+/// ZFSBootMenu properties on the production pool's boot environment container.
+pub fn set_remote_properties(machine: &Machine, sess: &ssh2::Session, address: &str) {
+    machine::remote_run(sess, address, "Set ZFSBootMenu command line", &format!(
+        "zfs set org.zfsbootmenu:commandline='{}' {}/{}",
+        machine.boot.commandline, overlay::ROOT_POOL, install::BOOT_CONTAINER
+    ));
+}
+
+
+/// Compare the ZFSBootMenu image on each ESP of the production machine.
+pub fn check_remote(machine: &Machine) {
+    let (sess, address) = machine::session(machine);
+    let mut checksums = Vec::new();
+    for (partition, _, _) in remote_esps(&sess, &address) {
+        let output = machine::remote_run(&sess, &address, &format!("Check ZFSBootMenu on {}", partition), &format!(
+            "mkdir -p {0} && mount -o ro {1} {0} && (sha256sum {0}{2} || true) && umount {0}", REMOTE_ESP_MOUNT, partition, EFI_PATH
+        ));
+        let checksum = output.split_whitespace().next().unwrap_or("missing").to_owned();
+        println!("{} {}", partition, checksum);
+        checksums.push(checksum);
+    }
+    checksums.dedup();
+    if checksums.len() != 1 || checksums[0] == "missing" {
+        halt!("The ESPs of {} are out of sync. Run 'ref zbm install {}' to bring them in line.", machine.name, machine.name);
+    }
+    println!("All ESPs of {} are in sync", machine.name);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(nics: &str) -> Machine {
+        serde_yaml::from_str(&format!("{{name: web1, disks: [], nics: {}}}", nics)).unwrap()
+    }
+
+    fn unlock(nic: &str, dhcp: bool) -> RemoteUnlock {
+        RemoteUnlock { nic: nic.to_owned(), dhcp, port: DEFAULT_UNLOCK_PORT, authorized_keys: Vec::new() }
+    }
+
+    #[test]
+    fn static_ip_arguments() {
+        let cases = [
+            ("[{name: eth0, mac: m, addresses: [192.168.1.10/24], gateway: 192.168.1.1}]", "ip=192.168.1.10::192.168.1.1:255.255.255.0:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [10.1.2.3/8]}]", "ip=10.1.2.3:::255.0.0.0:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [10.1.2.3/0]}]", "ip=10.1.2.3:::0.0.0.0:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [10.1.2.3/32]}]", "ip=10.1.2.3:::255.255.255.255:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [10.1.2.3]}]", "ip=10.1.2.3:::255.255.255.255:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [172.16.5.4/20]}]", "ip=172.16.5.4:::255.255.240.0:web1:eth0:none"),
+            ("[{name: eth0, mac: m, addresses: [192.168.1.10/25]}]", "ip=192.168.1.10:::255.255.255.128:web1:eth0:none"),
+            // IPv6 addresses are skipped
+            ("[{name: eth0, mac: m, addresses: [fe80::1/64, 192.168.1.10/24]}]", "ip=192.168.1.10:::255.255.255.0:web1:eth0:none"),
+            // the gateway of another NIC is used when the unlock NIC has none
+            ("[{name: eth0, mac: m, addresses: [10.0.0.2/24]}, {name: eth1, mac: m, addresses: [], gateway: 10.9.9.1}]", "ip=10.0.0.2::10.9.9.1:255.255.255.0:web1:eth0:none"),
+        ];
+        for (nics, expected) in cases {
+            assert_eq!(ip_argument(&machine(nics), &unlock("eth0", false)), expected, "{}", nics);
+        }
+    }
+
+    #[test]
+    fn dhcp_ip_argument() {
+        let machine = machine("[{name: eth0, mac: m, addresses: []}, {name: eth1, mac: m, addresses: [10.0.0.2/24]}]");
+        assert_eq!(ip_argument(&machine, &unlock("eth1", true)), "ip=:::::eth1:dhcp");
+    }
+}